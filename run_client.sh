@@ -16,6 +16,6 @@ if [ ! -f "$SCRIPT_DIR/target/release/$CLIENT_BIN" ]; then
     fi
 fi
 
-# Run the client
+# Run the client, passing on any command line flags
 echo "Starting the client..."
-"$SCRIPT_DIR/target/release/$CLIENT_BIN"
+"$SCRIPT_DIR/target/release/$CLIENT_BIN" "$@"
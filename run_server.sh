@@ -16,6 +16,6 @@ if [ ! -f "$SCRIPT_DIR/target/release/$SERVER_BIN" ]; then
     fi
 fi
 
-# Run the server
+# Run the server, passing on any command line flags
 echo "Starting the server..."
-"$SCRIPT_DIR/target/release/$SERVER_BIN"
+"$SCRIPT_DIR/target/release/$SERVER_BIN" "$@"
@@ -0,0 +1,75 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use crate::json_file;
+use crate::users::look_alike;
+
+// Hash a password with a fresh random salt, the result is a self-describing PHC string
+pub fn hash_password(password: &str) -> std::io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::other(e.to_string()))
+}
+
+// Check a password against a stored PHC string, any parse error counts as a mismatch
+pub fn verify_password(password: &str, stored_hash: &str) -> bool {
+    match PasswordHash::new(stored_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Registered accounts, kept in memory and saved as a JSON object of username -> password hash
+pub struct AccountStore {
+    path: PathBuf,
+    accounts: HashMap<String, String>,
+}
+
+impl AccountStore {
+    // Load the accounts file, a missing file simply means no accounts yet
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let accounts = json_file::load(&path)?;
+        Ok(AccountStore { path, accounts })
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.accounts.contains_key(username)
+    }
+
+    // The registered username this one is or looks just like
+    pub fn registered_as(&self, username: &str) -> Option<&str> {
+        self.accounts.get_key_value(username).map(|(registered, _)| registered.as_str()).or_else(|| self.lookalike(username))
+    }
+
+    // A registered username other than this one that looks just like it
+    pub fn lookalike(&self, username: &str) -> Option<&str> {
+        self.accounts.keys().map(String::as_str).find(|registered| *registered != username && look_alike(registered, username))
+    }
+
+    pub fn password_hash(&self, username: &str) -> Option<String> {
+        self.accounts.get(username).cloned()
+    }
+
+    // Store an already hashed password and write the whole file back
+    pub fn register(&mut self, username: &str, password_hash: String) -> std::io::Result<()> {
+        if self.is_registered(username) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("'{}' is already registered", username)));
+        }
+        self.accounts.insert(username.to_string(), password_hash);
+        if let Err(e) = self.save() {
+            self.accounts.remove(username);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        json_file::save(&self.path, &self.accounts)
+    }
+}
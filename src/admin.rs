@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use std::sync::Arc;
+use crate::bans::Ban;
+use crate::config::ServerConfig;
+use crate::protocol::now_timestamp;
+use crate::users::normalize_username;
+use crate::{ServerState, ShutdownRequest, broadcast_notice, finish_session};
+
+pub const ADMIN_HELP: &str = "\
+Commands:
+  /who                          list connected users with their client tokens
+  /kick <username> [reason]     disconnect a user
+  /ban <username> [reason]      disconnect a user and refuse their future logins
+  /unban <username>             allow a banned user to log in again
+  /announce <text>              send a notice to every connected user
+  /reload                       reload the configuration file
+  /stats                        show uptime and connection counts
+  /shutdown [seconds] [reason]  shut the server down, optionally after a countdown
+  /help                         show this help";
+
+// One request on the admin socket, sent as a single JSON line such as {"command":"kick","username":"bob"}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    List,
+    Kick {
+        username: String,
+        #[serde(default)]
+        reason: String,
+    },
+    Ban {
+        username: String,
+        #[serde(default)]
+        reason: String,
+    },
+    Unban {
+        username: String,
+    },
+    Announce {
+        text: String,
+    },
+    ReloadConfig,
+    Stats,
+    Shutdown {
+        #[serde(default)]
+        countdown_secs: Option<u64>, // the configured countdown when missing
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+// The answer to a request, also a single JSON line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Ok { message: String },
+    Error { message: String },
+    Users { users: Vec<UserEntry> },
+    Stats(ServerStats),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserEntry {
+    pub token: usize,
+    pub username: String,
+    pub registered: bool,
+    pub detached: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub uptime_secs: u64,
+    pub connections: usize,
+    pub users: usize,
+    pub detached: usize,
+    pub channels: usize,
+}
+
+impl AdminResponse {
+    fn ok(message: impl Into<String>) -> Self {
+        AdminResponse::Ok { message: message.into() }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        AdminResponse::Error { message: message.into() }
+    }
+}
+
+// Run one admin command typed at the console and return what to show the operator.
+// The leading slash is optional.
+pub async fn run_command(state: &ServerState, line: &str) -> String {
+    match parse_command(line) {
+        Ok(Some(request)) => format_response(&handle_request(state, request).await),
+        Ok(None) => String::new(),
+        Err(text) => text,
+    }
+}
+
+// Turn a console line into a request, the error is the text to show instead (usage or help)
+pub fn parse_command(line: &str) -> Result<Option<AdminRequest>, String> {
+    let line = line.trim();
+    let line = line.strip_prefix('/').unwrap_or(line);
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("").to_lowercase();
+    let argument = parts.next().unwrap_or("").trim();
+    // Commands aimed at a user take the username first and an optional reason after it
+    let (username, reason) = match argument.split_once(char::is_whitespace) {
+        Some((username, reason)) => (username.to_string(), reason.trim().to_string()),
+        None => (argument.to_string(), String::new()),
+    };
+    let request = match command.as_str() {
+        "" => return Ok(None),
+        "who" | "list" => AdminRequest::List,
+        "kick" if !argument.is_empty() => AdminRequest::Kick { username, reason },
+        "kick" => return Err("Usage: /kick <username> [reason]".to_string()),
+        "ban" if !argument.is_empty() => AdminRequest::Ban { username, reason },
+        "ban" => return Err("Usage: /ban <username> [reason]".to_string()),
+        "unban" if !argument.is_empty() => AdminRequest::Unban { username: argument.to_string() },
+        "unban" => return Err("Usage: /unban <username>".to_string()),
+        "announce" if !argument.is_empty() => AdminRequest::Announce { text: argument.to_string() },
+        "announce" => return Err("Usage: /announce <text>".to_string()),
+        "reload" => AdminRequest::ReloadConfig,
+        "stats" | "uptime" => AdminRequest::Stats,
+        "shutdown" => {
+            // An optional countdown in seconds comes first, the rest is the reason
+            let mut parts = argument.splitn(2, char::is_whitespace);
+            match parts.next().unwrap_or("").parse::<u64>() {
+                Ok(secs) => AdminRequest::Shutdown { countdown_secs: Some(secs), reason: parts.next().unwrap_or("").trim().to_string() },
+                Err(_) => AdminRequest::Shutdown { countdown_secs: None, reason: argument.to_string() },
+            }
+        }
+        "help" => return Err(ADMIN_HELP.to_string()),
+        _ => return Err(format!("Unknown command: {}. Type /help for the list of commands.", command)),
+    };
+    Ok(Some(request))
+}
+
+// Everything the console and the admin socket can do, answered from the shared server state
+pub async fn handle_request(state: &ServerState, request: AdminRequest) -> AdminResponse {
+    match request {
+        AdminRequest::List => AdminResponse::Users { users: list_users(state).await },
+        AdminRequest::Kick { username, reason } => {
+            let reason = if reason.is_empty() { "You have been kicked by an administrator.".to_string() } else { format!("You have been kicked: {}", reason) };
+            match kick(state, &username, &reason).await {
+                Some(token) => AdminResponse::ok(format!("Kicked {} (client {}).", username, token)),
+                None => AdminResponse::error(format!("User '{}' is not online.", username)),
+            }
+        }
+        AdminRequest::Ban { username, reason } => ban(state, &username, reason).await,
+        AdminRequest::Unban { username } => unban(state, &username).await,
+        AdminRequest::Announce { text } if text.trim().is_empty() => AdminResponse::error("The announcement cannot be empty."),
+        AdminRequest::Announce { text } => {
+            broadcast_notice(state, &format!("Announcement: {}", text.trim())).await;
+            AdminResponse::ok("Announcement sent.")
+        }
+        AdminRequest::ReloadConfig => reload_config(state).await,
+        AdminRequest::Stats => AdminResponse::Stats(stats(state).await),
+        AdminRequest::Shutdown { countdown_secs, reason } => shutdown(state, countdown_secs, reason),
+    }
+}
+
+// Text for the operator, shared by the console and termtalk-admin
+pub fn format_response(response: &AdminResponse) -> String {
+    match response {
+        AdminResponse::Ok { message } => message.clone(),
+        AdminResponse::Error { message } => format!("Error: {}", message),
+        AdminResponse::Users { users } if users.is_empty() => "Nobody is connected.".to_string(),
+        AdminResponse::Users { users } => {
+            let mut lines = vec![match users.len() {
+                1 => "1 user:".to_string(),
+                count => format!("{} users:", count),
+            }];
+            for user in users {
+                let mut flags = Vec::new();
+                if user.registered {
+                    flags.push("registered");
+                }
+                if user.detached {
+                    flags.push("detached");
+                }
+                let flags = if flags.is_empty() { String::new() } else { format!(" ({})", flags.join(", ")) };
+                lines.push(format!("  {:>6}  {}{}", user.token, user.username, flags));
+            }
+            lines.join("\n")
+        }
+        AdminResponse::Stats(stats) => format!(
+            "Uptime: {}\nConnections: {}\nLogged in users: {} ({} detached)\nChannels: {}",
+            format_duration(Duration::from_secs(stats.uptime_secs)),
+            stats.connections,
+            stats.users,
+            stats.detached,
+            stats.channels
+        ),
+    }
+}
+
+async fn list_users(state: &ServerState) -> Vec<UserEntry> {
+    let users = state.users.lock().await.users();
+    let sessions = state.sessions.lock().await;
+    let accounts = state.accounts.lock().await;
+    users
+        .into_iter()
+        .map(|(token, username)| UserEntry { token, registered: accounts.is_registered(&username), detached: sessions.is_detached(token), username })
+        .collect()
+}
+
+// Tell the user why, then end their session for good, a kicked session cannot be resumed.
+// Returns the client token, or None if the user is not online.
+async fn kick(state: &ServerState, username: &str, reason: &str) -> Option<usize> {
+    let token = state.users.lock().await.token_of(username)?;
+
+    let kicked = state.sessions.lock().await.kick(token, reason);
+    if !kicked {
+        // The connection already dropped, end the session that waits to be resumed
+        let detached = state.sessions.lock().await.take_detached(token);
+        if let Some(session) = detached {
+            let channels = session.channels.clone();
+            session.finish();
+            finish_session(state, token, username, channels).await;
+        }
+    }
+    Some(token)
+}
+
+// Refuse future logins of the username and its lookalikes, and disconnect it if it is online
+async fn ban(state: &ServerState, username: &str, reason: String) -> AdminResponse {
+    let username = normalize_username(username);
+    if username.is_empty() {
+        return AdminResponse::error("Username cannot be empty.");
+    }
+    let message = if reason.is_empty() { "You have been banned from this server.".to_string() } else { format!("You have been banned: {}", reason) };
+    if let Err(e) = state.bans.lock().await.ban(&username, Ban { reason, banned_at: now_timestamp() }) {
+        return AdminResponse::error(format!("Failed to save the ban list: {}", e));
+    }
+    match kick(state, &username, &message).await {
+        Some(token) => AdminResponse::ok(format!("Banned {} and disconnected client {}.", username, token)),
+        None => AdminResponse::ok(format!("Banned {}.", username)),
+    }
+}
+
+async fn unban(state: &ServerState, username: &str) -> AdminResponse {
+    let username = normalize_username(username);
+    match state.bans.lock().await.unban(&username) {
+        Ok(true) => AdminResponse::ok(format!("Unbanned {}.", username)),
+        Ok(false) => AdminResponse::error(format!("'{}' is not banned.", username)),
+        Err(e) => AdminResponse::error(format!("Failed to save the ban list: {}", e)),
+    }
+}
+
+// Settings that are only read at startup, a reload cannot change them
+fn restart_required(old: &ServerConfig, new: &ServerConfig) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    let mut check = |name, differs| {
+        if differs {
+            changed.push(name);
+        }
+    };
+    check("bind_address", old.bind_address != new.bind_address);
+    check("log_format", old.log_format != new.log_format);
+    check("broadcast_capacity", old.broadcast_capacity != new.broadcast_capacity);
+    check("accounts_file", old.accounts_file != new.accounts_file);
+    check("bans_file", old.bans_file != new.bans_file);
+    check("history_size", old.history_size != new.history_size);
+    check("store_path", old.store_path != new.store_path);
+    check("retention_days", old.retention_days != new.retention_days);
+    check("retention_max_rows", old.retention_max_rows != new.retention_max_rows);
+    check("transcript_dir", old.transcript_dir != new.transcript_dir);
+    check("transcript_format", old.transcript_format != new.transcript_format);
+    check("tls_cert", old.tls_cert != new.tls_cert);
+    check("tls_key", old.tls_key != new.tls_key);
+    check("admin_socket", old.admin_socket != new.admin_socket);
+    check("metrics_address", old.metrics_address != new.metrics_address);
+    changed
+}
+
+// Reading the files and reopening the log file block, so the reload runs off the async workers
+async fn reload_config(state: &ServerState) -> AdminResponse {
+    let reload = Arc::clone(&state.reload_config);
+    let config = match tokio::task::spawn_blocking(move || reload()).await {
+        Ok(Ok(config)) => config,
+        Ok(Err(e)) => return AdminResponse::error(format!("Invalid configuration, keeping the current one: {}", e)),
+        Err(e) => return AdminResponse::error(format!("Failed to reload the configuration: {}", e)),
+    };
+    let changed = restart_required(&state.config(), &config);
+    state.set_config(config);
+    if changed.is_empty() {
+        AdminResponse::ok("Configuration reloaded.")
+    } else {
+        AdminResponse::ok(format!("Configuration reloaded. Restart the server to apply: {}.", changed.join(", ")))
+    }
+}
+
+async fn stats(state: &ServerState) -> ServerStats {
+    ServerStats {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        connections: state.client_writers.lock().await.len(),
+        users: state.users.lock().await.len(),
+        detached: state.sessions.lock().await.detached_count(),
+        channels: state.channels.lock().await.channel_count(),
+    }
+}
+
+fn shutdown(state: &ServerState, countdown_secs: Option<u64>, reason: String) -> AdminResponse {
+    let config = state.config();
+    let countdown_secs = countdown_secs.unwrap_or(config.shutdown_countdown_secs);
+    let reason = if reason.trim().is_empty() { config.shutdown_message.clone() } else { reason };
+    if state.shutdown_requests.send(ShutdownRequest { reason, countdown_secs }).is_err() {
+        return AdminResponse::error("The server is already shutting down.");
+    }
+    match countdown_secs {
+        0 => AdminResponse::ok("Shutting down."),
+        secs => AdminResponse::ok(format!("Shutting down in {} seconds.", secs)),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, seconds) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}h {:02}m {:02}s", days, hours, minutes, seconds)
+    } else {
+        format!("{:02}h {:02}m {:02}s", hours, minutes, seconds)
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+use clap::{Parser, Subcommand};
+use termtalk::admin::{AdminRequest, AdminResponse, format_response};
+use termtalk::config::ServerConfig;
+
+/// Control a running TermTalk server through its admin socket
+#[derive(Parser)]
+#[command(name = "termtalk-admin", version, about)]
+struct Cli {
+    /// Configuration file the admin socket path is read from [default: config.toml]
+    #[arg(short, long, value_name = "FILE", global = true)]
+    config: Option<String>,
+    /// Admin socket of the server, overrides admin_socket from the configuration
+    #[arg(short, long, value_name = "PATH", global = true)]
+    socket: Option<String>,
+    /// Print the raw JSON response
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List connected users with their client tokens
+    #[command(alias = "who")]
+    List,
+    /// Disconnect a user
+    Kick {
+        username: String,
+        /// Reason shown to the user
+        reason: Vec<String>,
+    },
+    /// Disconnect a user and refuse their future logins
+    Ban {
+        username: String,
+        /// Reason shown to the user
+        reason: Vec<String>,
+    },
+    /// Allow a banned user to log in again
+    Unban { username: String },
+    /// Send a notice to every connected user
+    Announce {
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// Reload the server configuration file
+    Reload,
+    /// Show uptime and connection counts
+    Stats,
+    /// Shut the server down
+    Shutdown {
+        /// Warn the clients this many seconds before disconnecting them
+        #[arg(short = 't', long, value_name = "SECONDS")]
+        countdown: Option<u64>,
+        /// Reason shown to the clients
+        reason: Vec<String>,
+    },
+}
+
+impl From<Command> for AdminRequest {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::List => AdminRequest::List,
+            Command::Kick { username, reason } => AdminRequest::Kick { username, reason: reason.join(" ") },
+            Command::Ban { username, reason } => AdminRequest::Ban { username, reason: reason.join(" ") },
+            Command::Unban { username } => AdminRequest::Unban { username },
+            Command::Announce { text } => AdminRequest::Announce { text: text.join(" ") },
+            Command::Reload => AdminRequest::ReloadConfig,
+            Command::Stats => AdminRequest::Stats,
+            Command::Shutdown { countdown, reason } => AdminRequest::Shutdown { countdown_secs: countdown, reason: reason.join(" ") },
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let socket = match cli.socket {
+        Some(socket) => socket,
+        None => match ServerConfig::load(cli.config.as_deref()) {
+            Ok(config) if config.admin_socket.is_empty() => {
+                eprintln!("The admin socket is disabled in the configuration.");
+                return ExitCode::FAILURE;
+            }
+            Ok(config) => config.admin_socket,
+            Err(e) => {
+                eprintln!("Invalid configuration: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let (raw, response) = match send_request(&socket, &cli.command.into()) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("Cannot talk to the server on {}: {}", socket, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.json {
+        println!("{}", raw);
+    } else {
+        println!("{}", format_response(&response));
+    }
+    match response {
+        AdminResponse::Error { .. } => ExitCode::FAILURE,
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+// One request line out, one response line back
+fn send_request(socket: &str, request: &AdminRequest) -> std::io::Result<(String, AdminResponse)> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut raw = String::new();
+    if BufReader::new(stream).read_line(&mut raw)? == 0 {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the server closed the connection"));
+    }
+    let raw = raw.trim_end().to_string();
+    let response = serde_json::from_str(&raw)?;
+    Ok((raw, response))
+}
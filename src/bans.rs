@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use crate::json_file;
+use crate::users::username_keys;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub reason: String,
+    pub banned_at: i64, // seconds since the Unix epoch
+}
+
+// Usernames that may not log in, kept in a JSON file next to the accounts. A ban also covers
+// every username that looks like the banned one, the same way the user registry matches names.
+pub struct BanList {
+    path: PathBuf,
+    bans: HashMap<String, Ban>, // username as banned -> ban
+    keys: HashMap<String, String>, // both keys of every banned username -> username as banned
+}
+
+impl BanList {
+    // Load the bans file, a missing file simply means nobody is banned
+    pub fn load(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let bans: HashMap<String, Ban> = json_file::load(&path)?;
+        let mut list = BanList { path, bans: HashMap::new(), keys: HashMap::new() };
+        for (username, ban) in bans {
+            list.insert(username, ban);
+        }
+        Ok(list)
+    }
+
+    // The username as it was banned, if this one or one that looks like it is banned
+    fn banned_as(&self, username: &str) -> Option<&str> {
+        username_keys(username).iter().find_map(|key| self.keys.get(key)).map(String::as_str)
+    }
+
+    fn insert(&mut self, username: String, ban: Ban) -> Option<Ban> {
+        for key in username_keys(&username) {
+            self.keys.insert(key, username.clone());
+        }
+        self.bans.insert(username, ban)
+    }
+
+    fn remove(&mut self, username: &str) -> Option<Ban> {
+        let ban = self.bans.remove(username)?;
+        for key in username_keys(username) {
+            self.keys.remove(&key);
+        }
+        Some(ban)
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Ban> {
+        self.banned_as(username).and_then(|banned| self.bans.get(banned))
+    }
+
+    // Banning a name that is already banned in another spelling replaces that ban
+    pub fn ban(&mut self, username: &str, ban: Ban) -> std::io::Result<()> {
+        let username = self.banned_as(username).unwrap_or(username).to_string();
+        let previous = self.insert(username.clone(), ban);
+        if let Err(e) = self.save() {
+            match previous {
+                Some(previous) => self.insert(username, previous),
+                None => self.remove(&username),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Returns false if neither the username nor one that looks like it was banned
+    pub fn unban(&mut self, username: &str) -> std::io::Result<bool> {
+        let Some(username) = self.banned_as(username).map(str::to_string) else {
+            return Ok(false);
+        };
+        let previous = self.remove(&username).expect("banned username has a ban");
+        if let Err(e) = self.save() {
+            self.insert(username, previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        json_file::save(&self.path, &self.bans)
+    }
+}
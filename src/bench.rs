@@ -0,0 +1,264 @@
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use clap::Parser;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{Mutex, mpsc};
+use tokio_util::sync::CancellationToken;
+use tokio::time::timeout_at;
+use termtalk::channels::DEFAULT_CHANNEL;
+use termtalk::protocol::{CAP_CHANNELS, Frame, MAX_SERVER_FRAME_LEN, PROTOCOL_VERSION, encode, read_frame, software_version};
+
+/// Measure how many chat messages per second a running TermTalk server relays to a channel
+#[derive(Parser)]
+#[command(name = "termtalk-bench", version, about)]
+struct Cli {
+    /// Address of the server, which must accept guests over plain TCP
+    #[arg(short, long, value_name = "HOST:PORT", default_value = "127.0.0.1:8080")]
+    address: String,
+    /// Clients per round, one round for each count. The first client sends, all of them listen
+    #[arg(short = 'n', long, value_delimiter = ',', default_values_t = [1, 10, 100, 500])]
+    clients: Vec<usize>,
+    /// Messages sent in every round
+    #[arg(short, long, default_value_t = 1000)]
+    messages: usize,
+    /// Channel the benchmark talks in, followed by the number of the round
+    #[arg(long, default_value = "#bench")]
+    channel: String,
+    /// Give up on a round after this many seconds
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 60)]
+    timeout: u64,
+}
+
+// A logged in client that has joined the benchmark channel. The writer is shared because the
+// sending client answers pings while it is still sending.
+struct Bot {
+    reader: BufReader<OwnedReadHalf>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+}
+
+impl Bot {
+    async fn connect(address: &str, username: &str, channel: &str) -> std::io::Result<Bot> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+        let mut bot = Bot { reader: BufReader::new(reader), writer: Arc::new(Mutex::new(writer)) };
+        bot.send(&Frame::Hello { version: PROTOCOL_VERSION, software: software_version(), capabilities: vec![CAP_CHANNELS.to_string()] }).await?;
+        bot.expect(|frame| matches!(frame, Frame::Welcome { .. })).await?;
+        bot.send(&Frame::Login { username: username.to_string(), password: None }).await?;
+        bot.expect(|frame| matches!(frame, Frame::LoginAccepted { .. })).await?;
+        bot.send(&Frame::Join { channel: channel.to_string() }).await?;
+        bot.expect(|frame| matches!(frame, Frame::Joined { .. })).await?;
+        // Every login and logout is announced to the default channel with its member list,
+        // which would make the server busy with the bots rather than with the benchmark
+        bot.send(&Frame::Part { channel: DEFAULT_CHANNEL.to_string() }).await?;
+        bot.expect(|frame| matches!(frame, Frame::Parted { .. })).await?;
+        Ok(bot)
+    }
+
+    async fn send(&self, frame: &Frame) -> std::io::Result<()> {
+        self.writer.lock().await.write_all(encode(frame).as_bytes()).await
+    }
+
+    // Read until a frame matches, an error from the server ends the benchmark
+    async fn expect(&mut self, matches: impl Fn(&Frame) -> bool) -> std::io::Result<()> {
+        loop {
+            match self.next().await? {
+                Frame::Error { message } => return Err(std::io::Error::other(message)),
+                frame if matches(&frame) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    // The next frame, pings are answered on the way
+    async fn next(&mut self) -> std::io::Result<Frame> {
+        loop {
+            match read_frame(&mut self.reader, MAX_SERVER_FRAME_LEN).await? {
+                Some(Frame::Ping) => self.send(&Frame::Pong).await?,
+                Some(frame) => return Ok(frame),
+                None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    // Count the benchmark messages from the sender until the last one arrives,
+    // returns how many arrived and when the last one did
+    async fn listen(&mut self, plan: &RoundPlan) -> std::io::Result<(usize, Instant)> {
+        let last = format!("bench {}", plan.messages - 1);
+        let mut received = 0;
+        loop {
+            if let Frame::Chat { channel, from, text, .. } = self.next().await? {
+                if channel == plan.channel && from == plan.sender {
+                    received += 1;
+                    if text == last {
+                        return Ok((received, Instant::now()));
+                    }
+                }
+            }
+        }
+    }
+
+    // Keep reading until the round is over, then log out. A client that stopped reading would be
+    // disconnected by the server, and one that logged out early would be announced to the others.
+    async fn linger(mut self, over: &CancellationToken) {
+        loop {
+            tokio::select! {
+                _ = over.cancelled() => break,
+                frame = self.next() => if frame.is_err() {
+                    return;
+                },
+            }
+        }
+        let _ = self.send(&Frame::Quit).await;
+    }
+}
+
+// What every client of a round needs to know
+struct RoundPlan {
+    address: String,
+    round: usize,
+    channel: String,
+    sender: String, // username of the client sending the messages
+    messages: usize,
+    over: CancellationToken,
+}
+
+impl RoundPlan {
+    fn username(&self, client: usize) -> String {
+        format!("bench{}c{}", self.round, client)
+    }
+}
+
+// Writer of a logged in client, by its number
+type Connected = (usize, Arc<Mutex<OwnedWriteHalf>>);
+
+// One client of a round: log in, report the result of listening and stay until the round is over.
+// A client still logging in or waiting for the last message when the round ends gives up.
+async fn run_client(
+    plan: Arc<RoundPlan>,
+    client: usize,
+    connected: mpsc::UnboundedSender<std::io::Result<Connected>>,
+    results: mpsc::UnboundedSender<Option<(usize, Instant)>>,
+) {
+    let username = plan.username(client);
+    let bot = tokio::select! {
+        bot = Bot::connect(&plan.address, &username, &plan.channel) => bot,
+        _ = plan.over.cancelled() => return,
+    };
+    let mut bot = match bot {
+        Ok(bot) => bot,
+        Err(e) => {
+            let _ = connected.send(Err(e));
+            return;
+        }
+    };
+    let _ = connected.send(Ok((client, Arc::clone(&bot.writer))));
+    let result = tokio::select! {
+        result = bot.listen(&plan) => result,
+        _ = plan.over.cancelled() => return,
+    };
+    let _ = results.send(result.as_ref().ok().copied());
+    if result.is_ok() {
+        bot.linger(&plan.over).await;
+    }
+}
+
+// Result of one round
+struct Round {
+    elapsed: Duration,
+    delivered: usize, // messages that reached a client, at most clients * messages
+    failed: usize, // clients that lost their connection or did not finish in time
+}
+
+// Log in the clients, let the first one send the messages and wait until every client,
+// the sender included, received the last of them
+async fn run_round(cli: &Cli, round: usize, clients: usize) -> std::io::Result<Round> {
+    let plan = Arc::new(RoundPlan {
+        address: cli.address.clone(),
+        round,
+        channel: format!("{}{}", cli.channel, round),
+        sender: format!("bench{}c0", round),
+        messages: cli.messages,
+        over: CancellationToken::new(),
+    });
+    // Let the clients go home whichever way the round ends
+    let _over = plan.over.clone().drop_guard();
+    let (connected_tx, mut connected) = mpsc::unbounded_channel();
+    let (results_tx, mut results) = mpsc::unbounded_channel();
+    let tasks: Vec<_> = (0..clients).map(|client| tokio::spawn(run_client(Arc::clone(&plan), client, connected_tx.clone(), results_tx.clone()))).collect();
+    drop((connected_tx, results_tx));
+
+    let mut writer = None;
+    let login_deadline = Instant::now() + Duration::from_secs(cli.timeout);
+    for _ in 0..clients {
+        match timeout_at(login_deadline.into(), connected.recv()).await {
+            Ok(Some(Ok((0, sender)))) => writer = Some(sender),
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) => return Err(std::io::Error::other("a client task ended without logging in")),
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "the clients did not log in in time")),
+        }
+    }
+    let writer = writer.expect("the sending client logged in");
+
+    let started = Instant::now();
+    for i in 0..cli.messages {
+        let frame = Frame::Say { channel: plan.channel.clone(), text: format!("bench {}", i) };
+        writer.lock().await.write_all(encode(&frame).as_bytes()).await?;
+    }
+
+    let deadline = started + Duration::from_secs(cli.timeout);
+    let mut result = Round { elapsed: Duration::ZERO, delivered: 0, failed: clients };
+    for _ in 0..clients {
+        let Ok(Some(listened)) = timeout_at(deadline.into(), results.recv()).await else {
+            break;
+        };
+        if let Some((received, finished)) = listened {
+            result.delivered += received;
+            result.elapsed = result.elapsed.max(finished - started);
+            result.failed -= 1;
+        }
+    }
+    if result.failed > 0 {
+        result.elapsed = started.elapsed();
+    }
+    plan.over.cancel();
+    for task in tasks {
+        let _ = task.await;
+    }
+    Ok(result)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    if cli.messages == 0 || cli.clients.contains(&0) {
+        eprintln!("termtalk-bench: the message and client counts must be at least 1");
+        return ExitCode::FAILURE;
+    }
+
+    println!("{:>8} {:>9} {:>9} {:>12} {:>14} {:>8} {:>7}", "clients", "messages", "seconds", "messages/s", "deliveries/s", "lost", "failed");
+    for (round, &clients) in cli.clients.iter().enumerate() {
+        let result = match run_round(&cli, round, clients).await {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("termtalk-bench: round with {} clients failed: {}", clients, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let seconds = result.elapsed.as_secs_f64().max(f64::EPSILON);
+        let expected = clients * cli.messages;
+        println!(
+            "{:>8} {:>9} {:>9.3} {:>12.0} {:>14.0} {:>8} {:>7}",
+            clients,
+            cli.messages,
+            seconds,
+            cli.messages as f64 / seconds,
+            result.delivered as f64 / seconds,
+            expected - result.delivered,
+            result.failed,
+        );
+    }
+    ExitCode::SUCCESS
+}
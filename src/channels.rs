@@ -0,0 +1,299 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use crate::protocol::{Frame, encode, now_timestamp};
+use crate::transcripts::{TranscriptEvent, TranscriptWriter};
+
+// Every user is placed in this channel right after logging in
+pub const DEFAULT_CHANNEL: &str = "#general";
+
+const MAX_CHANNEL_NAME_LEN: usize = 32;
+
+// Turn user input such as "rust" or "#Rust" into "#rust", or None if the name is not allowed
+pub fn normalize_channel_name(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('#').to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() < MAX_CHANNEL_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Some(format!("#{}", name))
+    } else {
+        None
+    }
+}
+
+// A frame broadcast to a channel. It is encoded once and shared by every receiver, so handing it
+// to one more client costs a reference count instead of a copy. Chat lines and notices are
+// numbered, so a receiver that fell behind the channel can tell which of them it missed.
+#[derive(Debug)]
+pub struct Event {
+    pub seq: u64, // number of the latest chat line or notice of the channel, this one included
+    pub frame: Frame,
+    pub line: Arc<str>, // the frame as sent on the wire, newline included
+}
+
+impl Event {
+    pub fn new(seq: u64, frame: Frame) -> Arc<Self> {
+        let line = encode(&frame).into();
+        Arc::new(Event { seq, frame, line })
+    }
+}
+
+// A receiver of the channel's broadcasts and the number of the last message sent before it subscribed
+pub struct Subscription {
+    pub receiver: broadcast::Receiver<Arc<Event>>,
+    pub seq: u64,
+}
+
+struct Channel {
+    sender: broadcast::Sender<Arc<Event>>,
+    members: HashMap<usize, String>, // client token -> username
+    history: VecDeque<Arc<Event>>, // last chat lines and notices, oldest first, for replay and catch-up
+    seq: u64,
+}
+
+impl Channel {
+    fn subscribe(&self) -> Subscription {
+        Subscription { receiver: self.sender.subscribe(), seq: self.seq }
+    }
+}
+
+// Chat lines and notices are numbered and kept in the history, member lists are not
+fn is_message(frame: &Frame) -> bool {
+    matches!(frame, Frame::Chat { .. } | Frame::System { .. })
+}
+
+// All channels on the server, each with its own broadcast sender and member list
+pub struct ChannelRegistry {
+    channels: HashMap<String, Channel>,
+    capacity: usize,
+    history_size: usize, // messages replayed on join
+    retained: usize, // messages kept per channel, also those a lagging receiver skipped past the broadcast buffer
+    transcripts: Option<TranscriptWriter>,
+}
+
+impl ChannelRegistry {
+    pub fn new(capacity: usize, history_size: usize, transcripts: Option<TranscriptWriter>) -> Self {
+        ChannelRegistry { channels: HashMap::new(), capacity, history_size, retained: history_size.max(2 * capacity), transcripts }
+    }
+
+    // Add the client to the channel (creating it if needed) and subscribe to its broadcasts
+    pub fn join(&mut self, channel: &str, client_token: usize, username: &str) -> Subscription {
+        let capacity = self.capacity;
+        let entry = self.channels.entry(channel.to_string()).or_insert_with(|| Channel {
+            sender: broadcast::channel(capacity).0,
+            members: HashMap::new(),
+            history: VecDeque::new(),
+            seq: 0,
+        });
+        entry.members.insert(client_token, username.to_string());
+        entry.subscribe()
+    }
+
+    // Subscribe to an existing channel without changing its members, used when a session is resumed
+    pub fn subscribe(&self, channel: &str) -> Option<Subscription> {
+        self.channels.get(channel).map(Channel::subscribe)
+    }
+
+    // Remove the client from the channel, returns false if it was not a member.
+    // Empty channels are dropped, except for the default one.
+    pub fn part(&mut self, channel: &str, client_token: usize) -> bool {
+        let Some(entry) = self.channels.get_mut(channel) else {
+            return false;
+        };
+        let removed = entry.members.remove(&client_token).is_some();
+        if entry.members.is_empty() && channel != DEFAULT_CHANNEL {
+            self.channels.remove(channel);
+        }
+        removed
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_member(&self, channel: &str, client_token: usize) -> bool {
+        self.channels.get(channel).is_some_and(|entry| entry.members.contains_key(&client_token))
+    }
+
+    // Every channel the client is a member of
+    pub fn channels_of(&self, client_token: usize) -> Vec<String> {
+        self.channels.iter().filter(|(_, entry)| entry.members.contains_key(&client_token)).map(|(name, _)| name.clone()).collect()
+    }
+
+    // Sorted usernames of the channel members
+    pub fn members(&self, channel: &str) -> Vec<String> {
+        let mut users = self
+            .channels
+            .get(channel)
+            .map(|entry| entry.members.values().cloned().collect::<Vec<String>>())
+            .unwrap_or_default();
+        users.sort();
+        users
+    }
+
+    // The last messages of the channel up to the history size, oldest first
+    pub fn history(&self, channel: &str) -> Vec<Frame> {
+        let Some(entry) = self.channels.get(channel) else {
+            return Vec::new();
+        };
+        let skip = entry.history.len().saturating_sub(self.history_size);
+        entry.history.iter().skip(skip).map(|event| event.frame.clone()).collect()
+    }
+
+    // The messages a receiver skipped between the one numbered `after` and the broadcast it got next,
+    // as far as the history still has them, and how many of them are gone for good
+    pub fn missed(&self, channel: &str, after: u64, next: &Event) -> (Vec<Arc<Event>>, u64) {
+        let up_to = if is_message(&next.frame) { next.seq - 1 } else { next.seq };
+        let found: Vec<Arc<Event>> = self
+            .channels
+            .get(channel)
+            .map(|entry| entry.history.iter().filter(|event| event.seq > after && event.seq <= up_to).cloned().collect())
+            .unwrap_or_default();
+        let lost = up_to.saturating_sub(after).saturating_sub(found.len() as u64);
+        (found, lost)
+    }
+
+    // Broadcast a frame to every member of the channel, chat lines and notices are also kept in its history
+    pub fn send(&mut self, channel: &str, frame: Frame) {
+        if let (Some(transcripts), Frame::Chat { from, text, timestamp, .. }) = (&self.transcripts, &frame) {
+            let event = TranscriptEvent::Message { channel: channel.to_string(), from: from.clone(), text: text.clone() };
+            transcripts.record_at(*timestamp, event);
+        }
+        let retained = self.retained;
+        if let Some(entry) = self.channels.get_mut(channel) {
+            let message = is_message(&frame);
+            if message {
+                entry.seq += 1;
+            }
+            let event = Event::new(entry.seq, frame);
+            if message && retained > 0 {
+                if entry.history.len() >= retained {
+                    entry.history.pop_front();
+                }
+                entry.history.push_back(Arc::clone(&event));
+            }
+            let _ = entry.sender.send(event);
+        }
+    }
+
+    // Tell the channel that someone joined, with the refreshed member list
+    pub fn announce_join(&mut self, channel: &str, username: &str) {
+        self.record(TranscriptEvent::Join { channel: channel.to_string(), username: username.to_string() });
+        self.announce(channel, format!("{} has joined {}!", username, channel));
+    }
+
+    // Tell the channel that someone left, the notice says how
+    pub fn announce_leave(&mut self, channel: &str, username: &str, notice: String) {
+        self.record(TranscriptEvent::Leave { channel: channel.to_string(), username: username.to_string() });
+        self.announce(channel, notice);
+    }
+
+    // A server-wide notice, it goes into the transcript of every channel
+    pub fn record_notice(&self, text: &str) {
+        for channel in self.channels.keys() {
+            self.record(TranscriptEvent::Notice { channel: channel.clone(), text: text.to_string() });
+        }
+    }
+
+    fn record(&self, event: TranscriptEvent) {
+        if let Some(transcripts) = &self.transcripts {
+            transcripts.record(event);
+        }
+    }
+
+    // Broadcast the system notice and the refreshed member list to the channel
+    fn announce(&mut self, channel: &str, text: String) {
+        self.send(channel, Frame::System { channel: Some(channel.to_string()), text, timestamp: now_timestamp() });
+        let users = self.members(channel);
+        self.send(channel, Frame::UserList { channel: channel.to_string(), users });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    const CHANNEL: &str = "#test";
+
+    fn chat(i: u64) -> Frame {
+        Frame::Chat { channel: CHANNEL.to_string(), from: "alice".to_string(), text: format!("m{}", i), timestamp: 0 }
+    }
+
+    fn texts<'a>(frames: impl IntoIterator<Item = &'a Frame>) -> Vec<String> {
+        frames
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Chat { text, .. } => text.clone(),
+                other => panic!("unexpected frame {:?}", other),
+            })
+            .collect()
+    }
+
+    fn frames(events: &[Arc<Event>]) -> impl Iterator<Item = &Frame> {
+        events.iter().map(|event| &event.frame)
+    }
+
+    // A channel whose broadcasts hold 4 frames and which keeps 8 messages, 2 of them for replay
+    fn registry_with_messages(count: u64) -> (ChannelRegistry, Subscription) {
+        let mut registry = ChannelRegistry::new(4, 2, None);
+        let subscription = registry.join(CHANNEL, 1, "alice");
+        for i in 1..=count {
+            registry.send(CHANNEL, chat(i));
+        }
+        (registry, subscription)
+    }
+
+    // The broadcast a lagging receiver gets next, after being told how many it skipped
+    fn next_after_lag(subscription: &mut Subscription) -> (u64, Arc<Event>) {
+        let Err(TryRecvError::Lagged(skipped)) = subscription.receiver.try_recv() else {
+            panic!("the receiver did not lag");
+        };
+        (skipped, subscription.receiver.try_recv().expect("a broadcast after the lag"))
+    }
+
+    #[test]
+    fn resends_a_lag_the_channel_still_has() {
+        let (registry, mut subscription) = registry_with_messages(6);
+        let (skipped, next) = next_after_lag(&mut subscription);
+        assert_eq!((skipped, next.seq), (2, 3));
+        let (missed, lost) = registry.missed(CHANNEL, subscription.seq, &next);
+        assert_eq!(texts(frames(&missed)), ["m1", "m2"]);
+        assert_eq!(lost, 0);
+    }
+
+    #[test]
+    fn counts_what_fell_out_of_the_channel() {
+        let (registry, mut subscription) = registry_with_messages(20);
+        let (skipped, next) = next_after_lag(&mut subscription);
+        assert_eq!((skipped, next.seq), (16, 17));
+        // The channel keeps m13 to m20, m1 to m12 are gone
+        let (missed, lost) = registry.missed(CHANNEL, subscription.seq, &next);
+        assert_eq!(texts(frames(&missed)), ["m13", "m14", "m15", "m16"]);
+        assert_eq!(lost, 12);
+    }
+
+    #[test]
+    fn a_member_list_does_not_count_as_a_message() {
+        let (mut registry, mut subscription) = registry_with_messages(3);
+        registry.send(CHANNEL, Frame::UserList { channel: CHANNEL.to_string(), users: vec!["alice".to_string()] });
+        let mut next = None;
+        while let Ok(event) = subscription.receiver.try_recv() {
+            next = Some(event);
+        }
+        let next = next.expect("the member list was broadcast");
+        assert!(matches!(next.frame, Frame::UserList { .. }));
+        assert_eq!(next.seq, 3);
+        // A receiver that last saw m1 missed m2 and m3, the member list carries no message itself
+        let (missed, lost) = registry.missed(CHANNEL, 1, &next);
+        assert_eq!(texts(frames(&missed)), ["m2", "m3"]);
+        assert_eq!(lost, 0);
+    }
+
+    #[test]
+    fn replays_only_the_history_size() {
+        let (registry, _subscription) = registry_with_messages(6);
+        assert_eq!(texts(&registry.history(CHANNEL)), ["m5", "m6"]);
+    }
+}
@@ -1,17 +1,16 @@
-use chrono::Local;
 use tokio::net::TcpStream;
-use tokio::io::{AsyncBufReadExt};
+use tokio_rustls::TlsConnector;
+use tokio::io::{AsyncWriteExt, BufReader};
 use tokio::sync::broadcast;
 use tokio::sync::Mutex;
-use colored::*;
 use crossterm::{
     event::{self, Event, KeyCode},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use std::io::{self};
-use std::fs::OpenOptions;
 use std::sync::Arc;
+use std::collections::HashMap;
 use tui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout},
@@ -22,62 +21,260 @@ use tui::{
 };
 use tokio::time::{self, Duration};
 
-mod logging;
-mod utils;
 
-use logging::log_message;
-use utils::{write_to_stream, format_message};
+use tracing::{debug, error, info, warn};
+use termtalk::protocol::{Frame, MAX_SERVER_FRAME_LEN, PROTOCOL_VERSION, SUPPORTED_CAPABILITIES, encode, read_frame, software_version, write_frame};
+use termtalk::utils::{display_username, format_backlog_message, format_direct_message, format_message};
+use termtalk::channels::DEFAULT_CHANNEL;
+use termtalk::config::{ClientConfig, LogLevel, with_port};
+use clap::Parser;
+use termtalk::tls::{BoxedReader, BoxedWriter, client_connector, server_name, split_plain, split_tls};
+
+/// TermTalk terminal chat client
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file [default: config.toml]
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<String>,
+    /// Server to connect to, overrides server_address from the configuration
+    #[arg(short, long, value_name = "HOST:PORT")]
+    server: Option<String>,
+    /// Server port, keeping the host of the server address
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Log in with this username right away instead of asking for it
+    #[arg(short, long)]
+    username: Option<String>,
+    /// Log level: error, warn, info or debug
+    #[arg(short, long, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+}
+
+// Connect to the server (over TLS if a connector is given) and complete the hello/welcome exchange,
+// returns the stream halves and the session token
+async fn connect(config: &ClientConfig, tls: Option<&TlsConnector>) -> Result<(BufReader<BoxedReader>, BoxedWriter, String), String> {
+    let address = config.server_address.as_str();
+    let stream = TcpStream::connect(address).await.map_err(|e| format!("Cannot reach the server at {}: {}", address, e))?;
+    let (read_stream, mut write_stream) = match tls {
+        Some(connector) => {
+            let name = server_name(address, &config.tls_server_name).map_err(|e| e.to_string())?;
+            let tls_stream = connector.connect(name, stream).await.map_err(|e| format!("TLS handshake with {} failed: {}", address, e))?;
+            split_tls(tls_stream)
+        }
+        None => split_plain(stream),
+    };
+    let mut reader = BufReader::new(read_stream);
+
+    // Introduce ourselves with the protocol version and the capabilities we support
+    let hello = Frame::Hello {
+        version: PROTOCOL_VERSION,
+        software: software_version(),
+        capabilities: SUPPORTED_CAPABILITIES.iter().map(|cap| cap.to_string()).collect(),
+    };
+    write_stream.write_all(encode(&hello).as_bytes()).await.map_err(|e| format!("Failed to send hello to server: {}", e))?;
+
+    // Read the client token and the negotiated capabilities from the server
+    match read_frame(&mut reader, MAX_SERVER_FRAME_LEN).await {
+        Ok(Some(Frame::Welcome { version, token, .. })) if version == PROTOCOL_VERSION => Ok((reader, write_stream, token)),
+        Ok(Some(Frame::Welcome { version, software, .. })) => Err(format!(
+            "Server {} speaks protocol version {}, this client ({}) speaks version {}.",
+            software, version, software_version(), PROTOCOL_VERSION
+        )),
+        Ok(Some(Frame::Error { message })) => Err(message),
+        Ok(Some(_)) | Err(_) => Err("The server did not answer with a valid welcome, it may be running an incompatible version.".to_string()),
+        Ok(None) => Err("The server closed the connection during the handshake.".to_string()),
+    }
+}
+
+// Reconnect after the connection dropped: resume the old session if the server still has it,
+// otherwise log in again with the same credentials. Returns the accepted frame along with the new connection.
+async fn reconnect(
+    config: &ClientConfig,
+    tls: Option<&TlsConnector>,
+    old_token: &str,
+    username: &str,
+    password: &str,
+) -> Result<(BufReader<BoxedReader>, BoxedWriter, String, Frame), String> {
+    let (mut reader, mut write_stream, token) = connect(config, tls).await?;
+
+    let resume = Frame::Resume { token: old_token.to_string() };
+    write_stream.write_all(encode(&resume).as_bytes()).await.map_err(|e| e.to_string())?;
+    match read_frame(&mut reader, MAX_SERVER_FRAME_LEN).await.map_err(|e| e.to_string())? {
+        Some(accepted @ Frame::LoginAccepted { .. }) => return Ok((reader, write_stream, token, accepted)),
+        Some(Frame::Error { message }) => message,
+        _ => return Err("Unexpected server response to resume.".to_string()),
+    };
+
+    // The session is gone, start a new one under the same name
+    let login = Frame::Login {
+        username: username.to_string(),
+        password: if password.is_empty() { None } else { Some(password.to_string()) },
+    };
+    write_stream.write_all(encode(&login).as_bytes()).await.map_err(|e| e.to_string())?;
+    match read_frame(&mut reader, MAX_SERVER_FRAME_LEN).await.map_err(|e| e.to_string())? {
+        Some(accepted @ Frame::LoginAccepted { .. }) => Ok((reader, write_stream, token, accepted)),
+        Some(Frame::Error { message }) => Err(message),
+        _ => Err("Unexpected server response to login.".to_string()),
+    }
+}
+
+// Send the login frame and wait for the verdict: the accepted username and whether it is registered,
+// or the reason it was refused. None means the connection is gone.
+async fn log_in(
+    write_stream: &Arc<Mutex<BoxedWriter>>,
+    reader: &mut BufReader<BoxedReader>,
+    username: &str,
+    password: &str,
+) -> Option<Result<(String, bool), String>> {
+    let login = Frame::Login {
+        username: username.to_string(),
+        password: if password.is_empty() { None } else { Some(password.to_string()) },
+    };
+    if write_frame(write_stream, &login).await.is_err() {
+        debug!("failed to send the login");
+        return None;
+    }
+
+    let response = match read_frame(reader, MAX_SERVER_FRAME_LEN).await {
+        Ok(Some(frame)) => frame,
+        _ => {
+            debug!("failed to read the login response");
+            return None;
+        }
+    };
+
+    debug!(?response, "login response");
+
+    // Check if the server accepted the username
+    match response {
+        Frame::LoginAccepted { username, registered, .. } => Some(Ok((username, registered))),
+        Frame::Error { message } => Some(Err(format!("Error: {}", message))),
+        _ => Some(Err("Unexpected server response. Please try again.".to_string())),
+    }
+}
+
+// Turn a line typed by the user into a frame: slash commands or a chat line for the current channel
+fn parse_input(input: &str, current_channel: &str) -> Result<Frame, String> {
+    let input = input.trim();
+    if !input.starts_with('/') {
+        return Ok(Frame::Say { channel: current_channel.to_string(), text: input.to_string() });
+    }
+
+    let mut parts = input.splitn(2, char::is_whitespace);
+    let command = parts.next().unwrap_or("");
+    let argument = parts.next().unwrap_or("").trim();
+    match command {
+        "/join" if !argument.is_empty() => Ok(Frame::Join { channel: argument.to_string() }),
+        "/join" => Err("Usage: /join <channel>".to_string()),
+        "/part" if argument.is_empty() => Ok(Frame::Part { channel: current_channel.to_string() }),
+        "/part" => Ok(Frame::Part { channel: argument.to_string() }),
+        "/register" if !argument.is_empty() => Ok(Frame::Register { password: argument.to_string() }),
+        "/register" => Err("Usage: /register <password>".to_string()),
+        "/msg" => {
+            let mut parts = argument.splitn(2, char::is_whitespace);
+            let to = parts.next().unwrap_or("").trim_start_matches('@');
+            let text = parts.next().unwrap_or("").trim();
+            if to.is_empty() || text.is_empty() {
+                Err("Usage: /msg <user> <text>".to_string())
+            } else {
+                Ok(Frame::PrivateMessage { to: to.to_string(), text: text.to_string() })
+            }
+        }
+        _ => Err(format!("Unknown command: {}", command)),
+    }
+}
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    // Load the configuration and set up TLS before taking over the terminal so errors stay readable.
+    // Command line flags take precedence over the environment and the config file.
+    let cli = Cli::parse();
+    let mut config = match ClientConfig::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(server) = cli.server {
+        config.server_address = server;
+    }
+    if let Some(port) = cli.port {
+        config.server_address = with_port(&config.server_address, port);
+    }
+    if let Some(level) = cli.log_level {
+        config.log_level = level;
+    }
+    let tls = if config.tls {
+        match client_connector(&config.tls_ca_file, &config.tls_fingerprint) {
+            Ok(connector) => Some(connector),
+            Err(e) => {
+                eprintln!("Invalid TLS configuration: {}", e);
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+
+    // Logs go to the log file only, the terminal belongs to the chat window
+    termtalk::logging::init(&config.log_settings())?;
+
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("client.log")?;
-    let log_file = Arc::new(Mutex::new(log_file));
-
-    // Log terminal initialization
-    log_message(&log_file, "[DEBUG] Terminal initialized successfully").await;
+    debug!("terminal initialized");
 
     // Create a broadcast channel for message broadcasting
-    let (sender, mut receiver) = broadcast::channel::<(String, String)>(32);
+    let (sender, mut receiver) = broadcast::channel::<Frame>(32);
 
     // Connect to the server
-    log_message(&log_file, "[DEBUG] Connecting to server...").await;
-    let stream = match TcpStream::connect("127.0.0.1:8080").await {
-        Ok(stream) => {
-            log_message(&log_file, "[DEBUG] Connected to server").await;
-            stream
+    info!(address = %config.server_address, "connecting");
+    let (mut reader, write_stream, client_token) = match connect(&config, tls.as_ref()).await {
+        Ok(connection) => {
+            info!("connected");
+            connection
         }
-        Err(_) => {
-            log_message(&log_file, "[DEBUG] Failed to connect to the server").await;
+        Err(message) => {
+            // Leave the alternate screen so the reason stays visible in the terminal
+            error!(error = %message, "failed to connect");
+            disable_raw_mode()?;
+            execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+            eprintln!("Failed to connect: {}", message);
             return Ok(());
         }
     };
-    let (read_stream, write_stream) = stream.into_split();
-    let mut reader = tokio::io::BufReader::new(read_stream);
 
     // Wrap write_stream in an Arc<Mutex> for shared ownership
     let write_stream = Arc::new(Mutex::new(write_stream));
+    // The token is a session secret, so it is kept out of the log
+    debug!("received session token");
 
-    // Read the client token from the server
-    let mut token_message = String::new();
-    if reader.read_line(&mut token_message).await.is_err() {
-        log_message(&log_file, "[DEBUG] Failed to read token from server").await;
-        return Ok(());
-    }
-    let client_token = token_message.trim().split_whitespace().last().unwrap_or("unknown").to_string();
-    log_message(&log_file, &format!("[DEBUG] Client token: {}", client_token)).await;
-
-    // Prompt the client for a username
+    // Prompt the client for a username and, for registered accounts, a password.
+    // A username from the command line is tried first, the prompt only shows up if it is refused.
     let mut username = String::new();
+    let mut password = String::new();
+    let mut editing_password = false;
     let mut error_message = String::new();
-    loop {
+    let mut accepted = None;
+    if let Some(name) = cli.username {
+        match log_in(&write_stream, &mut reader, name.trim(), "").await {
+            Some(Ok(login)) => accepted = Some(login),
+            Some(Err(message)) => error_message = message,
+            None => return Ok(()),
+        }
+        username = name;
+        // A registered username still needs its password
+        editing_password = true;
+    }
+    let registered = loop {
+	if let Some((accepted_username, is_registered)) = accepted.take() {
+            username = accepted_username;
+            break is_registered;
+	}
 	terminal.draw(|f| {
             let size = f.size();
             let chunks = Layout::default()
@@ -91,8 +288,15 @@ async fn main() -> std::io::Result<()> {
 		)
 		.split(size);
             
-            // Render username input area
-            let input_block = Paragraph::new(format!("Enter your username: {}\n\nPress 'Esc' to quit.", username))
+            // Render username and password input area, the password is masked
+            let marker = |active: bool| if active { ">" } else { " " };
+            let input_block = Paragraph::new(format!(
+		"{} Enter your username: {}\n{} Password (leave empty to log in as guest): {}\n\nPress 'Tab' to switch fields, 'Enter' to log in, 'Esc' to quit.",
+		marker(!editing_password),
+		username,
+		marker(editing_password),
+		"*".repeat(password.chars().count()),
+            ))
 		.block(Block::default().borders(Borders::ALL));
             f.render_widget(input_block, chunks[0]);
             
@@ -113,48 +317,35 @@ async fn main() -> std::io::Result<()> {
                     if username.trim().is_empty() {
 			error_message = "Error: Username cannot be empty!".to_string();
                     } else {
-			if write_to_stream(&write_stream, &format!("{}\n", username)).await.is_err() {
-                            log_message(&log_file, "[DEBUG] Failed to send username to server").await;
-                            return Ok(());
-			}
-			
-			let mut response = String::new();
-			if reader.read_line(&mut response).await.is_err() {
-                            log_message(&log_file, "[DEBUG] Failed to read server response").await;
-                            return Ok(());
-			}
-			
-			// Log the server's response
-			log_message(&log_file, &format!("[DEBUG] Server response: {}", response.trim())).await;
-			
-			// Filter out the "Enter your username: " prefix from the response
-			let response = response.trim().strip_prefix("Enter your username: ").unwrap_or(response.trim());
-			
-			// Check if the server accepted the username
-			if response == "SUCCESS: Username accepted." {
-                            break; 
-			} else if response == "ERROR: Username is already taken. Please choose a different one." {
-                            error_message = response.to_string();
-                            username.clear(); 
-                            continue; 
-			} else {
-                            error_message = "Unexpected server response. Please try again.".to_string();
-                            username.clear(); 
-                            continue; 
+			match log_in(&write_stream, &mut reader, username.trim(), &password).await {
+                            Some(Ok((accepted_username, is_registered))) => {
+				username = accepted_username;
+				break is_registered;
+                            }
+                            Some(Err(message)) => {
+				error_message = message;
+				username.clear();
+				password.clear();
+				editing_password = false;
+                            }
+                            None => return Ok(()),
 			}
                     }
 		}
+		KeyCode::Tab => {
+                    editing_password = !editing_password;
+		}
 		KeyCode::Backspace => {
-                    username.pop();
+                    if editing_password { password.pop(); } else { username.pop(); }
                     error_message.clear();
 		}
 		KeyCode::Char(c) => {
-                    username.push(c);
+                    if editing_password { password.push(c); } else { username.push(c); }
                     error_message.clear();
 		}
 		KeyCode::Esc => {
                     // Quit the application immediately
-                    log_message(&log_file, "[DEBUG] Quitting application from login screen").await;
+                    info!("quitting from the login screen");
                     disable_raw_mode()?;
                     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
                     return Ok(());
@@ -162,46 +353,89 @@ async fn main() -> std::io::Result<()> {
 		_ => {}
             }
 	}
-    }
+    };
     
     // Transition to chat state
-    log_message(&log_file, "[DEBUG] Transitioning to chat state").await;
+    debug!("logged in, switching to the chat screen");
     terminal.clear()?;
     
-    // Spawn a task to handle the client
+    // Spawn a task to handle the client, it reconnects on its own when the connection drops
     let sender_clone = sender.clone();
-    let username_clone = username.clone();
-    let log_file_clone = Arc::clone(&log_file);
-    let client_token_clone = client_token.clone();
     let write_stream_clone = Arc::clone(&write_stream);
+    let username_clone = username.clone();
+    let password_clone = password.clone();
+    let tls_clone = tls.clone();
+    let config_clone = config.clone();
     tokio::spawn(async move {
-        log_message(&log_file_clone, &format!("[DEBUG] Spawning client handler task for token {}", client_token_clone)).await;
-        let mut input = String::new();
+        debug!("starting the connection task");
+        let mut client_token = client_token;
         loop {
-            input.clear();
-            if reader.read_line(&mut input).await.is_err() {
-                log_message(&log_file_clone, "[DEBUG] Disconnected from server").await;
-                break;
+            loop {
+                let frame = match read_frame(&mut reader, MAX_SERVER_FRAME_LEN).await {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+			warn!("disconnected from the server");
+			break;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+			warn!(error = %e, "ignoring malformed frame from the server");
+			continue;
+                    }
+                    Err(_) => {
+			warn!("disconnected from the server");
+			break;
+                    }
+		};
+		
+		if frame == Frame::Ping {
+                    if write_frame(&write_stream_clone, &Frame::Pong).await.is_err() {
+			warn!("failed to send pong");
+			break;
+                    }
+                    continue;
+		}
+		if let Frame::Disconnect { reason } = frame {
+                    // Closed on purpose by the server, reconnecting would not help
+                    info!(%reason, "disconnected by the server");
+                    let _ = sender_clone.send(Frame::System { channel: None, text: "Disconnected by the server. Press Esc to quit.".to_string(), timestamp: 0 });
+                    return;
+		}
+
+		debug!(?frame, "received frame");
+		let _ = sender_clone.send(frame);
             }
-	    
-            if input.trim() == "PING" {
-                if write_to_stream(&write_stream_clone, "PONG\n").await.is_err() {
-                    log_message(&log_file_clone, "[DEBUG] Failed to send pong to server").await;
-                    break;
-                }
-                continue;
+
+            // Connection lost, keep trying with a growing delay
+            let _ = sender_clone.send(Frame::System { channel: None, text: "Connection lost, reconnecting...".to_string(), timestamp: 0 });
+            let mut delay = Duration::from_secs(1);
+            loop {
+		time::sleep(delay).await;
+		match reconnect(&config_clone, tls_clone.as_ref(), &client_token, &username_clone, &password_clone).await {
+                    Ok((new_reader, new_write_stream, new_token, accepted)) => {
+			info!("reconnected");
+			*write_stream_clone.lock().await = new_write_stream;
+			reader = new_reader;
+			client_token = new_token;
+			let _ = sender_clone.send(accepted);
+			break;
+                    }
+                    Err(e) => {
+			warn!(error = %e, "reconnect failed");
+			delay = (delay * 2).min(Duration::from_secs(30));
+                    }
+		}
             }
-	    
-            let timestamp = Local::now().format("[%d.%m.%Y %H:%M]").to_string();
-            let message = format!("{} {}", timestamp.black(), input.trim());
-            log_message(&log_file_clone, &format!("[DEBUG] Broadcasting message from token {}: {}", client_token_clone, message)).await;
-            let _ = sender_clone.send((username_clone.clone(), input.trim().to_string()));
         }
-        log_message(&log_file_clone, &format!("[DEBUG] Client handler task for token {} exited", client_token_clone)).await;
     });
     
     // Chat UI loop
     let mut messages: Vec<String> = Vec::new();
+    if !registered {
+	messages.push(format_message(None, "SERVER", "You are logged in as a guest, use /register <password> to claim this username.", true, &username, 0));
+    }
+    let mut user_lists: HashMap<String, Vec<String>> = HashMap::new();
+    let mut joined_channels: Vec<String> = Vec::new();
+    let mut current_channel = DEFAULT_CHANNEL.to_string();
     let mut input_text = String::new();
     let mut scroll_offset: u16 = 0;
     let mut interval = time::interval(Duration::from_millis(100));
@@ -222,25 +456,32 @@ async fn main() -> std::io::Result<()> {
 			.split(f.size());
 		    
                     if show_user_list {
-			// Render the user list screen
-			let user_list: Vec<&String> = messages.iter().filter(|msg| msg.starts_with("USERLIST:")).collect();
-			let user_list_text = user_list.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join("\n");
+			// Render the user list screen for the current channel
+			let user_list_text = user_lists
+                            .get(&current_channel)
+                            .map(|users| users.iter().map(|user| display_username(user)).collect::<Vec<String>>().join("\n"))
+                            .unwrap_or_default();
 			let user_list_block = Paragraph::new(format!("{}\n\nPress 'r' to return to chat.", user_list_text))
-                            .block(Block::default().borders(Borders::ALL).title("User List"))
+                            .block(Block::default().borders(Borders::ALL).title(format!("Users in {}", current_channel)))
                             .scroll((scroll_offset, 0));
 			f.render_widget(user_list_block, chunks[0]);
                     } else {
-			// Render the chat screen, filtering out USERLIST: messages
-			let chat_messages: Vec<&String> = messages.iter().filter(|msg| !msg.starts_with("USERLIST:")).collect();
-			let chat_messages_text = chat_messages.iter().map(|s| s.as_str()).collect::<Vec<&str>>().join("\n");
+			// Render the chat screen
+			let chat_messages_text = messages.join("\n");
+			let title = format!("Channels: {} (Tab to switch)", joined_channels.join(" "));
 			let message_block = Paragraph::new(chat_messages_text)
-                            .block(Block::default().borders(Borders::ALL))
+                            .block(Block::default().borders(Borders::ALL).title(title))
                             .scroll((scroll_offset, 0));
 			f.render_widget(message_block, chunks[0]);
                     }
 		    
-                    // Render the input block with the username prefix
-                    let input_block = Paragraph::new(format!("{}: {}", username, input_text))
+                    // Render the input block with the channel and username prefix
+                    // Never echo a password typed after /register
+                    let shown_input = match input_text.strip_prefix("/register ") {
+			Some(password) => format!("/register {}", "*".repeat(password.chars().count())),
+			None => input_text.clone(),
+                    };
+                    let input_block = Paragraph::new(format!("[{}] {}: {}", current_channel, username, shown_input))
 			.block(Block::default().borders(Borders::ALL));
                     f.render_widget(input_block, chunks[1]);
 		})?;
@@ -248,30 +489,36 @@ async fn main() -> std::io::Result<()> {
             event = async { event::read() } => {
 		if let Event::Key(key) = event? {
                     match key.code {
-			KeyCode::Enter => {
-                            if !input_text.is_empty() {
-				if write_to_stream(&write_stream, &format!("{}\n", input_text.trim())).await.is_err() {
-                                    log_message(&log_file, "[DEBUG] Failed to send message to server").await;
-                                    break;
+			KeyCode::Enter if !input_text.is_empty() => {
+                            match parse_input(&input_text, &current_channel) {
+				Ok(frame) => {
+                                    if write_frame(&write_stream, &frame).await.is_err() {
+					warn!("failed to send the message");
+					break;
+                                    }
 				}
-				input_text.clear();
+				Err(error) => messages.push(format_message(None, "SERVER", &error, true, &username, 0)),
                             }
+                            input_text.clear();
+			}
+			KeyCode::Tab if !joined_channels.is_empty() => {
+                            // Cycle through the joined channels
+                            let next = joined_channels.iter().position(|c| *c == current_channel).map_or(0, |i| (i + 1) % joined_channels.len());
+                            current_channel = joined_channels[next].clone();
 			}
 			KeyCode::Backspace => {
                             input_text.pop();
 			}
 			KeyCode::Char(c) => {
                             if key.modifiers.contains(event::KeyModifiers::CONTROL) {
-				match c {
-                                    'l' => {
-					// Request the user list from the server
-					if write_to_stream(&write_stream, "GET_USERLIST\n").await.is_err() {
-                                            log_message(&log_file, "[DEBUG] Failed to request user list from server").await;
-                                            break;
-					}
-					show_user_list = true;
+				// Ignore Ctrl combinations other than Ctrl+L
+				if c == 'l' {
+                                    // Request the user list from the server
+                                    if write_frame(&write_stream, &Frame::GetUserList { channel: current_channel.clone() }).await.is_err() {
+					warn!("failed to request the user list");
+					break;
                                     }
-                                    _ => {} // Ignore other Ctrl combinations
+                                    show_user_list = true;
 				}
                             } else if show_user_list && c == 'r' {
 				show_user_list = false;
@@ -292,28 +539,83 @@ async fn main() -> std::io::Result<()> {
             }
 	}
 	
-	while let Ok((sender_username, message)) = receiver.try_recv() {
-            if message.starts_with("USERLIST:") {
-		// Update the user list
-		let user_list = message.trim_start_matches("USERLIST: ").to_string();
-		messages.retain(|msg| !msg.starts_with("USERLIST:")); 
-		messages.push(format!("USERLIST: {}", user_list)); 
-		continue;
-            }
-	    
-            if message.contains("Enter your username:") {
-		continue;
+	while let Ok(frame) = receiver.try_recv() {
+            match frame {
+		Frame::UserList { channel, users } => {
+                    // Update the user list of that channel
+                    user_lists.insert(channel, users);
+		}
+		Frame::LoginAccepted { resumed, .. } => {
+                    // Back after a reconnect, a fresh session starts over in the default channel
+                    if resumed {
+			messages.push(format_message(None, "SERVER", "Reconnected, session resumed.", true, &username, 0));
+                    } else {
+			joined_channels.clear();
+			user_lists.clear();
+			messages.push(format_message(None, "SERVER", "Reconnected with a new session, messages sent in the meantime were lost.", true, &username, 0));
+                    }
+		}
+		Frame::Joined { channel } => {
+                    // A resumed session confirms the channels it was already in
+                    if !joined_channels.contains(&channel) {
+			joined_channels.push(channel.clone());
+			current_channel = channel;
+                    }
+		}
+		Frame::Parted { channel } => {
+                    joined_channels.retain(|c| *c != channel);
+                    user_lists.remove(&channel);
+                    if current_channel == channel {
+			current_channel = joined_channels.first().cloned().unwrap_or_default();
+                    }
+		}
+		Frame::History { channel, messages: backlog } => {
+                    for frame in backlog {
+			match frame {
+                            Frame::Chat { from, text, timestamp, .. } => messages.push(format_backlog_message(&channel, Some(&from), &text, timestamp)),
+                            Frame::System { text, timestamp, .. } => messages.push(format_backlog_message(&channel, None, &text, timestamp)),
+                            _ => {}
+			}
+                    }
+		}
+		Frame::OfflineMessages { messages: offline } => {
+                    messages.push(format_message(None, "SERVER", "While you were away:", true, &username, 0));
+                    for frame in offline {
+			match frame {
+                            Frame::Chat { channel, from, text, timestamp } => messages.push(format_message(Some(&channel), &from, &text, false, &username, timestamp)),
+                            Frame::Direct { from, to, text, timestamp } => messages.push(format_direct_message(&from, &to, &text, &username, timestamp)),
+                            _ => {}
+			}
+                    }
+		}
+		Frame::Chat { channel, from, text, timestamp } => {
+                    messages.push(format_message(Some(&channel), &from, &text, false, &username, timestamp));
+                    debug!(%channel, %from, "received message");
+		}
+		Frame::Direct { from, to, text, timestamp } => {
+                    messages.push(format_direct_message(&from, &to, &text, &username, timestamp));
+                    debug!(%from, %to, "received private message");
+		}
+		Frame::System { channel, text, timestamp } => {
+                    messages.push(format_message(channel.as_deref(), "SERVER", &text, true, &username, timestamp));
+                    debug!(%text, "received server notice");
+		}
+		Frame::Error { message } => {
+                    messages.push(format_message(None, "SERVER", &format!("Error: {}", message), true, &username, 0));
+                    warn!(%message, "error from the server");
+		}
+		other => {
+                    debug!(frame = ?other, "ignoring unexpected frame");
+		}
             }
-	    
-            let is_server_message = message.starts_with("SERVER:");
-            let formatted_message = format_message(&sender_username, &message, is_server_message, &username);
-            messages.push(formatted_message);
-            log_message(&log_file, &format!("[DEBUG] Received message from {}: {}", sender_username, message)).await;
 	}
     }
     
+    // Tell the server we left on purpose so it does not keep the session around
+    let _ = write_frame(&write_stream, &Frame::Quit).await;
+
     // Clean up terminal after exit
-    log_message(&log_file, "[DEBUG] Cleaning up terminal").await;
+    debug!("restoring the terminal");
     disable_raw_mode()?;
     execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
     Ok(())
@@ -0,0 +1,459 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Read when no path is given explicitly, a missing file means all defaults
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+// How much goes into the log file, each level includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level '{}', expected error, warn, info or debug", value)),
+        }
+    }
+}
+
+// How log lines are written, plain text for people or one JSON object per line for tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", value)),
+        }
+    }
+}
+
+// When the log file is moved aside and a new one started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Daily,
+    Size,
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "never" => Ok(LogRotation::Never),
+            "daily" => Ok(LogRotation::Daily),
+            "size" => Ok(LogRotation::Size),
+            _ => Err(format!("unknown log rotation '{}', expected never, daily or size", value)),
+        }
+    }
+}
+
+// Layout of the chat transcripts, IRC-style text or one JSON object per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    Text,
+    Jsonl,
+}
+
+impl FromStr for TranscriptFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Ok(TranscriptFormat::Text),
+            "jsonl" => Ok(TranscriptFormat::Jsonl),
+            _ => Err(format!("unknown transcript format '{}', expected text or jsonl", value)),
+        }
+    }
+}
+
+// What happens when a client's outbound queue is full because it does not read fast enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest, // make room by dropping the oldest queued frame, the client is told how many it lost
+    Disconnect, // drop the connection, the client can resume its session
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().replace('-', "_").as_str() {
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy '{}', expected drop_oldest or disconnect", value)),
+        }
+    }
+}
+
+// What happens to terminal control sequences, control characters and bidi overrides in messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SanitizePolicy {
+    Strip, // remove them, line breaks and tabs become spaces
+    Escape, // show them as visible escapes such as \u{1b}
+    Reject, // refuse the message with an error to the sender
+}
+
+impl FromStr for SanitizePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "strip" => Ok(SanitizePolicy::Strip),
+            "escape" => Ok(SanitizePolicy::Escape),
+            "reject" => Ok(SanitizePolicy::Reject),
+            _ => Err(format!("unknown sanitize policy '{}', expected strip, escape or reject", value)),
+        }
+    }
+}
+
+// How frames are queued and written to each client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundSettings {
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+    pub write_timeout: Duration,
+}
+
+// Everything the logging subsystem needs, taken from either config section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogSettings {
+    pub file: String,
+    pub level: LogLevel,
+    pub format: LogFormat,
+    pub rotation: LogRotation,
+    pub max_size_mb: u64,
+    pub max_files: usize,
+}
+
+// Replace the port of a "host:port" address, as done by the --port flags
+pub fn with_port(address: &str, port: u16) -> String {
+    let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address);
+    format!("{}:{}", host, port)
+}
+
+// Layout of config.toml, both binaries read their own section
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerConfig,
+    client: ClientConfig,
+}
+
+// Server settings from the [server] section of config.toml, overridden by TERMTALK_* environment variables
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub log_file: String,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub log_rotation: LogRotation,
+    pub log_max_size_mb: u64, // size at which the log is rotated with the "size" rotation
+    pub log_max_files: usize, // rotated logs to keep, 0 keeps them all
+    pub max_clients: usize, // 0 means no limit
+    pub broadcast_capacity: usize, // frames buffered per channel, each channel keeps twice as many messages for a client further behind to catch up
+    pub ping_interval_secs: u64, // idle time before the server pings a client
+    pub ping_timeout_secs: u64, // how long to wait for the pong before disconnecting
+    pub login_timeout_secs: u64, // time a connection gets to log in, the user may still be typing their name
+    pub outbound_queue_size: usize, // frames queued per client while its connection is busy
+    pub outbound_overflow: OverflowPolicy,
+    pub write_timeout_secs: u64, // a client that accepts nothing for this long is disconnected
+    pub sanitize: SanitizePolicy, // applied to chat lines and private messages before they are relayed
+    pub accounts_file: String,
+    pub bans_file: String,
+    pub allow_guests: bool,
+    pub resume_grace_secs: u64,
+    pub history_size: usize,
+    pub store_path: String, // empty disables the message store
+    pub retention_days: u64,
+    pub retention_max_rows: u64,
+    pub transcript_dir: String, // one directory per channel with a file per day, empty disables transcripts
+    pub transcript_format: TranscriptFormat,
+    pub tls_cert: String, // TLS is enabled when both the certificate and the key are set
+    pub tls_key: String,
+    pub shutdown_message: String, // sent to every client when the server stops
+    pub shutdown_countdown_secs: u64, // warn the clients this long before disconnecting them
+    pub shutdown_timeout_secs: u64, // how long to wait for the connections to close
+    pub admin_socket: String, // Unix socket for termtalk-admin, empty disables it
+    pub metrics_address: String, // HTTP listener for /metrics and /healthz, empty disables it
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "127.0.0.1:8080".to_string(),
+            log_file: "server.log".to_string(),
+            log_level: LogLevel::Debug,
+            log_format: LogFormat::Text,
+            log_rotation: LogRotation::Daily,
+            log_max_size_mb: 10,
+            log_max_files: 7,
+            max_clients: 0,
+            broadcast_capacity: 256,
+            ping_interval_secs: 15,
+            ping_timeout_secs: 15,
+            login_timeout_secs: 300,
+            outbound_queue_size: 256,
+            outbound_overflow: OverflowPolicy::DropOldest,
+            write_timeout_secs: 10,
+            sanitize: SanitizePolicy::Strip,
+            accounts_file: "accounts.json".to_string(),
+            bans_file: "bans.json".to_string(),
+            allow_guests: true,
+            resume_grace_secs: 60,
+            history_size: 50,
+            store_path: "messages.db".to_string(),
+            retention_days: 30,
+            retention_max_rows: 100_000,
+            transcript_dir: "transcripts".to_string(),
+            transcript_format: TranscriptFormat::Text,
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            shutdown_message: "The server is shutting down.".to_string(),
+            shutdown_countdown_secs: 0,
+            shutdown_timeout_secs: 10,
+            admin_socket: "termtalk.sock".to_string(),
+            metrics_address: String::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    // Load the [server] section of the config file, then apply the environment on top
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        let mut config = read_config_file(path)?.server;
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_string("TERMTALK_BIND_ADDRESS", &mut self.bind_address);
+        env_string("TERMTALK_SERVER_LOG", &mut self.log_file);
+        env_parse("TERMTALK_LOG_LEVEL", &mut self.log_level)?;
+        env_parse("TERMTALK_LOG_FORMAT", &mut self.log_format)?;
+        env_parse("TERMTALK_LOG_ROTATION", &mut self.log_rotation)?;
+        env_parse("TERMTALK_LOG_MAX_SIZE_MB", &mut self.log_max_size_mb)?;
+        env_parse("TERMTALK_LOG_MAX_FILES", &mut self.log_max_files)?;
+        env_parse("TERMTALK_MAX_CLIENTS", &mut self.max_clients)?;
+        env_parse("TERMTALK_BROADCAST_CAPACITY", &mut self.broadcast_capacity)?;
+        env_parse("TERMTALK_PING_INTERVAL_SECS", &mut self.ping_interval_secs)?;
+        env_parse("TERMTALK_PING_TIMEOUT_SECS", &mut self.ping_timeout_secs)?;
+        env_parse("TERMTALK_LOGIN_TIMEOUT_SECS", &mut self.login_timeout_secs)?;
+        env_parse("TERMTALK_OUTBOUND_QUEUE_SIZE", &mut self.outbound_queue_size)?;
+        env_parse("TERMTALK_OUTBOUND_OVERFLOW", &mut self.outbound_overflow)?;
+        env_parse("TERMTALK_WRITE_TIMEOUT_SECS", &mut self.write_timeout_secs)?;
+        env_parse("TERMTALK_SANITIZE", &mut self.sanitize)?;
+        env_string("TERMTALK_ACCOUNTS_FILE", &mut self.accounts_file);
+        env_string("TERMTALK_BANS_FILE", &mut self.bans_file);
+        env_bool("TERMTALK_ALLOW_GUESTS", &mut self.allow_guests)?;
+        env_parse("TERMTALK_RESUME_GRACE_SECS", &mut self.resume_grace_secs)?;
+        env_parse("TERMTALK_HISTORY_SIZE", &mut self.history_size)?;
+        env_string("TERMTALK_STORE_PATH", &mut self.store_path);
+        env_parse("TERMTALK_RETENTION_DAYS", &mut self.retention_days)?;
+        env_parse("TERMTALK_RETENTION_MAX_ROWS", &mut self.retention_max_rows)?;
+        env_string("TERMTALK_TRANSCRIPT_DIR", &mut self.transcript_dir);
+        env_parse("TERMTALK_TRANSCRIPT_FORMAT", &mut self.transcript_format)?;
+        env_string("TERMTALK_TLS_CERT", &mut self.tls_cert);
+        env_string("TERMTALK_TLS_KEY", &mut self.tls_key);
+        env_string("TERMTALK_SHUTDOWN_MESSAGE", &mut self.shutdown_message);
+        env_parse("TERMTALK_SHUTDOWN_COUNTDOWN_SECS", &mut self.shutdown_countdown_secs)?;
+        env_parse("TERMTALK_SHUTDOWN_TIMEOUT_SECS", &mut self.shutdown_timeout_secs)?;
+        env_string("TERMTALK_ADMIN_SOCKET", &mut self.admin_socket);
+        env_string("TERMTALK_METRICS_ADDRESS", &mut self.metrics_address);
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.bind_address.trim().is_empty() {
+            return Err("server.bind_address cannot be empty".to_string());
+        }
+        if self.broadcast_capacity == 0 {
+            return Err("server.broadcast_capacity must be at least 1".to_string());
+        }
+        if self.ping_interval_secs == 0 || self.ping_timeout_secs == 0 || self.login_timeout_secs == 0 {
+            return Err("server.ping_interval_secs, server.ping_timeout_secs and server.login_timeout_secs must be at least 1".to_string());
+        }
+        if self.outbound_queue_size == 0 || self.write_timeout_secs == 0 {
+            return Err("server.outbound_queue_size and server.write_timeout_secs must be at least 1".to_string());
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            return Err("server.tls_cert and server.tls_key must be set together".to_string());
+        }
+        if self.log_file.trim().is_empty() || self.log_max_size_mb == 0 {
+            return Err("server.log_file cannot be empty and server.log_max_size_mb must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn log_settings(&self) -> LogSettings {
+        LogSettings {
+            file: self.log_file.clone(),
+            level: self.log_level,
+            format: self.log_format,
+            rotation: self.log_rotation,
+            max_size_mb: self.log_max_size_mb,
+            max_files: self.log_max_files,
+        }
+    }
+
+    pub fn outbound_settings(&self) -> OutboundSettings {
+        OutboundSettings {
+            queue_size: self.outbound_queue_size,
+            overflow: self.outbound_overflow,
+            write_timeout: Duration::from_secs(self.write_timeout_secs),
+        }
+    }
+}
+
+// Client settings from the [client] section of config.toml, overridden by TERMTALK_* environment variables
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    pub server_address: String,
+    pub log_file: String,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub log_rotation: LogRotation,
+    pub log_max_size_mb: u64,
+    pub log_max_files: usize,
+    pub tls: bool,
+    pub tls_ca_file: String, // CA certificate to trust instead of the public roots
+    pub tls_fingerprint: String, // SHA-256 of the server certificate, for self-signed ones
+    pub tls_server_name: String, // name to check the certificate against, defaults to the host
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server_address: "127.0.0.1:8080".to_string(),
+            log_file: "client.log".to_string(),
+            log_level: LogLevel::Debug,
+            log_format: LogFormat::Text,
+            log_rotation: LogRotation::Daily,
+            log_max_size_mb: 10,
+            log_max_files: 7,
+            tls: false,
+            tls_ca_file: String::new(),
+            tls_fingerprint: String::new(),
+            tls_server_name: String::new(),
+        }
+    }
+}
+
+impl ClientConfig {
+    // Load the [client] section of the config file, then apply the environment on top
+    pub fn load(path: Option<&str>) -> Result<Self, String> {
+        let mut config = read_config_file(path)?.client;
+        config.apply_env()?;
+        if config.server_address.trim().is_empty() {
+            return Err("client.server_address cannot be empty".to_string());
+        }
+        if config.log_file.trim().is_empty() || config.log_max_size_mb == 0 {
+            return Err("client.log_file cannot be empty and client.log_max_size_mb must be at least 1".to_string());
+        }
+        // Trusting a specific certificate only makes sense over TLS
+        if !config.tls_ca_file.is_empty() || !config.tls_fingerprint.is_empty() {
+            config.tls = true;
+        }
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_string("TERMTALK_SERVER_ADDRESS", &mut self.server_address);
+        env_string("TERMTALK_CLIENT_LOG", &mut self.log_file);
+        env_parse("TERMTALK_LOG_LEVEL", &mut self.log_level)?;
+        env_parse("TERMTALK_LOG_FORMAT", &mut self.log_format)?;
+        env_parse("TERMTALK_LOG_ROTATION", &mut self.log_rotation)?;
+        env_parse("TERMTALK_LOG_MAX_SIZE_MB", &mut self.log_max_size_mb)?;
+        env_parse("TERMTALK_LOG_MAX_FILES", &mut self.log_max_files)?;
+        env_bool("TERMTALK_TLS", &mut self.tls)?;
+        env_string("TERMTALK_TLS_CA", &mut self.tls_ca_file);
+        env_string("TERMTALK_TLS_FINGERPRINT", &mut self.tls_fingerprint);
+        env_string("TERMTALK_TLS_SERVER_NAME", &mut self.tls_server_name);
+        Ok(())
+    }
+
+    pub fn log_settings(&self) -> LogSettings {
+        LogSettings {
+            file: self.log_file.clone(),
+            level: self.log_level,
+            format: self.log_format,
+            rotation: self.log_rotation,
+            max_size_mb: self.log_max_size_mb,
+            max_files: self.log_max_files,
+        }
+    }
+}
+
+// A file named explicitly (or in TERMTALK_CONFIG) has to exist, the default config.toml is optional
+fn read_config_file(path: Option<&str>) -> Result<ConfigFile, String> {
+    let explicit = path.map(str::to_string).or_else(|| env::var("TERMTALK_CONFIG").ok());
+    let path = explicit.clone().unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string());
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && explicit.is_none() => return Ok(ConfigFile::default()),
+        Err(e) => return Err(format!("cannot read {}: {}", path, e)),
+    };
+    toml::from_str(&contents).map_err(|e| format!("invalid {}: {}", path, e))
+}
+
+fn env_string(name: &str, target: &mut String) {
+    if let Ok(value) = env::var(name) {
+        *target = value;
+    }
+}
+
+fn env_parse<T: FromStr>(name: &str, target: &mut T) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Ok(value) = env::var(name) {
+        *target = value.trim().parse().map_err(|e| format!("{}: invalid value '{}': {}", name, value, e))?;
+    }
+    Ok(())
+}
+
+fn env_bool(name: &str, target: &mut bool) -> Result<(), String> {
+    if let Ok(value) = env::var(name) {
+        *target = parse_bool(&value).ok_or_else(|| format!("{}: '{}' is not a valid boolean, use true or false", name, value))?;
+    }
+    Ok(())
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
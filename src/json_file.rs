@@ -0,0 +1,36 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+
+// Read a JSON file the server keeps its state in, a missing or empty file gives the default
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> std::io::Result<T> {
+    match fs::read_to_string(path) {
+        Ok(contents) if contents.trim().is_empty() => Ok(T::default()),
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+// Write to a temporary file first so a crash never leaves a half written file behind
+pub fn save<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    let contents = serde_json::to_string_pretty(value)?;
+    let tmp_path = path.with_extension("tmp");
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        // Only the server user should be able to read the files, the accounts hold password hashes
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
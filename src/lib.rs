@@ -1,122 +1,611 @@
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::BufReader;
 use tokio::time::{Duration, timeout};
 use tokio::sync::broadcast;
-use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, error, info, warn};
+use std::sync::{Arc, RwLock};
 use tokio::sync::Mutex;
 use std::collections::HashMap;
-use utils::write_to_stream;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use accounts::{AccountStore, hash_password};
+use bans::BanList;
+use channels::{ChannelRegistry, DEFAULT_CHANNEL, Event, Subscription, normalize_channel_name};
+use config::ServerConfig;
+use mailbox::{OfflineMailbox, mentioned_usernames};
+use metrics::{Metrics, increment};
+use protocol::{CAP_DISCONNECT, CAP_HISTORY, Frame, MAX_FRAME_LEN, encode, now_timestamp, read_frame};
+use sanitize::{REJECTED_MESSAGE, sanitize_frame};
+use sessions::{DetachedSession, SessionInfo, SessionRegistry};
+use store::MessageStore;
+use supervisor::{DisconnectReason, Supervisor};
+use outbound::Outbound;
+use tls::BoxedReader;
+use transcripts::{TranscriptEvent, TranscriptWriter};
+use users::UserRegistry;
 
-mod utils;
+pub mod accounts;
+pub mod admin;
+pub mod bans;
+pub mod channels;
+pub mod config;
+pub mod json_file;
+pub mod logging;
+pub mod mailbox;
+pub mod metrics;
+pub mod outbound;
+pub mod protocol;
+pub mod sanitize;
+pub mod sessions;
+pub mod store;
+pub mod supervisor;
+pub mod tls;
+pub mod transcripts;
+pub mod users;
+pub mod utils;
 
-async fn send_user_list(write_stream: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>, token_username_map: &Arc<Mutex<HashMap<usize, String>>>) -> std::io::Result<()> {
-    let map = token_username_map.lock().await;
-    let user_list = map.values().cloned().collect::<Vec<String>>().join(", ");
-    write_to_stream(write_stream, &format!("USERLIST: {}\n", user_list)).await
+// Everything the connection tasks share, cheap to clone since every field is an Arc
+#[derive(Clone)]
+pub struct ServerState {
+    pub users: Arc<Mutex<UserRegistry>>, // usernames of live and detached sessions
+    pub channels: Arc<Mutex<ChannelRegistry>>,
+    pub client_writers: Arc<Mutex<HashMap<usize, Outbound>>>,
+    pub accounts: Arc<Mutex<AccountStore>>,
+    pub sessions: Arc<Mutex<SessionRegistry>>,
+    pub mailbox: Arc<Mutex<OfflineMailbox>>,
+    pub bans: Arc<Mutex<BanList>>,
+    pub config: Arc<RwLock<Arc<ServerConfig>>>, // replaced as a whole when the configuration is reloaded
+    pub reload_config: Arc<dyn Fn() -> Result<ServerConfig, String> + Send + Sync>, // reads the configuration again
+    pub store: Option<MessageStore>,
+    pub transcripts: Option<TranscriptWriter>,
+    pub metrics: Arc<Metrics>,
+    pub shutdown: CancellationToken, // cancelled when the server shuts down, every connection then ends
+    pub shutdown_requests: mpsc::UnboundedSender<ShutdownRequest>, // asks the accept loop to start the shutdown
+    pub started_at: Instant,
 }
 
-pub async fn handle_client(
-    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
-    write_stream: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-    sender: broadcast::Sender<(String, String)>,
-    mut receiver: broadcast::Receiver<(String, String)>,
-    my_username: String,
+impl ServerState {
+    // The current configuration, settings read through it follow a reload
+    pub fn config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn set_config(&self, config: ServerConfig) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+}
+
+// Why and how soon the server should stop, from a signal or an administrator
+#[derive(Debug, Clone)]
+pub struct ShutdownRequest {
+    pub reason: String,
+    pub countdown_secs: u64,
+}
+
+// Send a server notice to every connected client, regardless of their channels
+pub async fn broadcast_notice(state: &ServerState, text: &str) {
+    let writers: Vec<Outbound> = state.client_writers.lock().await.values().cloned().collect();
+    let notice: Arc<str> = encode(&Frame::System { channel: None, text: text.to_string(), timestamp: now_timestamp() }).into();
+    state.channels.lock().await.record_notice(text);
+    for writer in writers {
+        let _ = writer.send_line(Arc::clone(&notice));
+    }
+}
+
+fn send_error(write_stream: &Outbound, message: &str) -> std::io::Result<()> {
+    write_stream.send(&Frame::Error { message: message.to_string() })
+}
+
+// Queue a frame for a known user who is not connected, returns the username as the user spells it,
+// or None if the user is online or unknown. The user registry stays locked while queueing, so a
+// login in the meantime cannot miss the frame.
+async fn queue_offline(state: &ServerState, username: &str, frame: &Frame) -> Option<String> {
+    let users = state.users.lock().await;
+    if users.is_online(username) {
+        return None;
+    }
+    let mut mailbox = state.mailbox.lock().await;
+    let known = match mailbox.known_as(username) {
+        Some(known) => known.to_string(),
+        None => state.accounts.lock().await.registered_as(username)?.to_string(),
+    };
+    mailbox.push(&known, frame.clone());
+    Some(known)
+}
+
+// Deliver a private message to the connection of the target user and echo it back to the sender
+async fn send_direct_message(
+    to: &str,
+    text: String,
+    write_stream: &Outbound,
+    my_username: &str,
     client_token: usize,
-    token_username_map: Arc<Mutex<HashMap<usize, String>>>,
-) {
-    println!("DEBUG: Handling client {} with username: {}", client_token, my_username);
+    state: &ServerState,
+) -> std::io::Result<()> {
+    let text = text.trim().to_string();
+    if text.is_empty() {
+        return Ok(());
+    }
 
-    let mut reader = reader;
+    // Look the target up by username, then find the writer of that connection
+    let (target_token, to) = {
+        let users = state.users.lock().await;
+        let target_token = users.token_of(to);
+        // Address the target the way they spell their name
+        let to = target_token.and_then(|token| users.username(token)).unwrap_or(to).to_string();
+        (target_token, to)
+    };
+    let target_writer = match target_token {
+        Some(token) => state.client_writers.lock().await.get(&token).cloned(),
+        None => None,
+    };
+    let message = Frame::Direct { from: my_username.to_string(), to: to.to_string(), text, timestamp: now_timestamp() };
+    let record = |state: &ServerState| {
+        increment(&state.metrics.private_messages);
+        if let Some(store) = &state.store {
+            store.record(&message);
+        }
+        if let (Some(transcripts), Frame::Direct { from, to, text, timestamp }) = (&state.transcripts, &message) {
+            transcripts.record_at(*timestamp, TranscriptEvent::Direct { from: from.clone(), to: to.clone(), text: text.clone() });
+        }
+    };
+    let Some(target_writer) = target_writer else {
+        // A user whose connection just dropped gets it when the session is resumed
+        let queued = match target_token {
+            Some(token) => state.sessions.lock().await.push_missed(token, message.clone()),
+            None => false,
+        };
+        if queued {
+            record(state);
+            return write_stream.send(&message);
+        }
+        // Someone who is offline gets it the next time they log in
+        if target_token.is_some() {
+            return send_error(write_stream, &format!("User '{}' is not online.", to));
+        }
+        if let Some(to) = queue_offline(state, &to, &message).await {
+            record(state);
+            write_stream.send(&message)?;
+            let notice = Frame::System { channel: None, text: format!("{} is offline, the message will be delivered when they log in.", to), timestamp: now_timestamp() };
+            return write_stream.send(&notice);
+        }
+        return send_error(write_stream, &format!("User '{}' is not online.", to));
+    };
 
-    // Use the wrapped write_stream for all write operations
-    let write_stream_clone = Arc::clone(&write_stream);
+    debug!(to, "routing private message");
+    if target_writer.send(&message).is_err() {
+        return send_error(write_stream, &format!("Failed to deliver the message to '{}'.", to));
+    }
+    record(state);
+    if target_token != Some(client_token) {
+        write_stream.send(&message)?;
+    }
+    Ok(())
+}
 
-    // Spawn a task to handle incoming messages from the client
-    let message_handler = tokio::spawn(async move {
-        println!("DEBUG: Spawning task for client {} messages and pings", client_token);
-        
-        let mut buf = String::new();
-        loop {
-            buf.clear();
-            
-            match timeout(Duration::from_secs(15), reader.read_line(&mut buf)).await {
-                Ok(Ok(n)) if n > 0 => {
-                    let trimmed = buf.trim();
-                    
-                    if trimmed == "PONG" {
-                        println!("DEBUG: Received PONG from client {}", client_token);
-                        continue;
-                    }
-                    
-                    if trimmed == "GET_USERLIST" {
-                        if send_user_list(&write_stream_clone, &token_username_map).await.is_err() {
-                            println!("DEBUG: Failed to send user list to client {}", client_token);
-                        }
-                        continue;
-                    }
-                    
-                    // Broadcast the message only once
-                    let message = format!("{}: {}\n", my_username, trimmed);
-                    println!("DEBUG: Broadcasting message from client {}: {}", client_token, message);
-                    let _ = sender.send((my_username.clone(), message));
-                }
-                Ok(Ok(_)) | Ok(Err(_)) => {
-                    println!("DEBUG: Client {} disconnected", client_token);
-                    break;
-                }
-                Err(_) => {
-                    println!("DEBUG: Timeout from client {}, sending PING", client_token);
-                    if write_stream_clone.lock().await.write_all(b"PING\n").await.is_err() {
-                        println!("DEBUG: Failed to send PING to client {}", client_token);
-                        break;
-                    }
-                }
-            }
+// Claim the current guest username with a password
+async fn register_account(password: String, write_stream: &Outbound, my_username: &str, state: &ServerState) -> std::io::Result<()> {
+    if password.is_empty() {
+        return send_error(write_stream, "Password cannot be empty.");
+    }
+    if state.accounts.lock().await.is_registered(my_username) {
+        return send_error(write_stream, &format!("'{}' is already registered.", my_username));
+    }
+
+    // Hashing is deliberately slow, keep it off the async worker threads
+    let password_hash = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(password_hash)) => password_hash,
+        _ => return send_error(write_stream, "Failed to register the account."),
+    };
+    let result = state.accounts.lock().await.register(my_username, password_hash);
+    match result {
+        Ok(()) => {
+            info!(username = my_username, "registered account");
+            let notice = Frame::System {
+                channel: None,
+                text: format!("The username '{}' is now registered to you.", my_username),
+                timestamp: now_timestamp(),
+            };
+            write_stream.send(&notice)
+        }
+        Err(e) => {
+            error!(username = my_username, error = %e, "failed to register account");
+            send_error(write_stream, "Failed to register the account.")
         }
-        
-        // Broadcast disconnect message only once
-        let disconnect_message = format!("SERVER: {} has left the chat!\n", my_username);
-        println!("DEBUG: Broadcasting disconnect message: {}", disconnect_message);
-        let _ = sender.send((my_username.clone(), disconnect_message));
-
-        // Remove the user from the token_username_map
-        let mut map = token_username_map.lock().await;
-        map.remove(&client_token);
-        println!("DEBUG: Removed token-username mapping: {} -> {}", client_token, my_username);
-
-        // Send the updated user list to all clients
-        let user_list = map.values().cloned().collect::<Vec<String>>().join(", ");
-        let _ = sender.send(("SERVER".to_string(), format!("USERLIST: {}\n", user_list)));
-    });
-    
-    // Spawn a task to handle broadcast messages and forward them to the client
-    let write_stream_clone = Arc::clone(&write_stream);
-    let broadcast_handler = tokio::spawn(async move {
+    }
+}
+
+// Forward everything broadcast in one channel to the client. A client that falls further behind
+// than the channel buffers gets what it skipped from the history before the next frame.
+fn spawn_forwarder(
+    subscription: Subscription,
+    write_stream: Outbound,
+    channel: String,
+    state: &ServerState,
+    supervisor: &Supervisor,
+) -> JoinHandle<()> {
+    let channels = Arc::clone(&state.channels);
+    let metrics = Arc::clone(&state.metrics);
+    let connection = supervisor.clone();
+    supervisor.spawn(async move {
+        let Subscription { mut receiver, seq: mut last_seq } = subscription;
+        let mut lagged = false;
         loop {
             match receiver.recv().await {
-                Ok((_sender_username, message)) => {
+                Ok(event) => {
+                    if lagged {
+                        lagged = false;
+                        if let Err(e) = catch_up(&channel, last_seq, &event, &channels, &write_stream).await {
+                            connection.stop(DisconnectReason::WriteError(e.to_string()));
+                            break;
+                        }
+                    }
+                    last_seq = event.seq;
                     // Forward ALL messages to the client, regardless of sender
-                    if write_stream_clone.lock().await.write_all(message.as_bytes()).await.is_err() {
-                        println!("DEBUG: Failed to forward message to client {}", client_token);
+                    if let Err(e) = write_stream.send_event(&event) {
+                        connection.stop(DisconnectReason::WriteError(e.to_string()));
                         break;
                     }
                 }
-                Err(_) => {
-                    println!("DEBUG: Broadcast channel closed for client {}", client_token);
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    // The oldest frames were overwritten, the next one tells how far to go back
+                    warn!(channel, skipped, "client fell behind the channel");
+                    metrics.record_lag(skipped);
+                    lagged = true;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    debug!(channel, "broadcast channel closed");
                     break;
                 }
             }
         }
-    });
+    })
+}
+
+// Resend the messages a lagging client skipped in a channel, say how many are gone for good
+// and refresh its member list, which may have changed in the meantime
+async fn catch_up(
+    channel: &str,
+    last_seq: u64,
+    next: &Event,
+    channels: &Arc<Mutex<ChannelRegistry>>,
+    write_stream: &Outbound,
+) -> std::io::Result<()> {
+    let (missed, lost, users) = {
+        let registry = channels.lock().await;
+        let (missed, lost) = registry.missed(channel, last_seq, next);
+        (missed, lost, registry.members(channel))
+    };
+    debug!(channel, resent = missed.len(), lost, "catching up");
+    if lost > 0 {
+        let unit = if lost == 1 { "message" } else { "messages" };
+        let text = format!("Your connection fell behind, {} {} in {} could not be delivered.", lost, unit, channel);
+        write_stream.send(&Frame::System { channel: Some(channel.to_string()), text, timestamp: now_timestamp() })?;
+    }
+    for event in &missed {
+        write_stream.send_event(event)?;
+    }
+    write_stream.send(&Frame::UserList { channel: channel.to_string(), users })
+}
 
-    // Wait for both tasks to complete
-    let (message_handler_result, broadcast_handler_result) = tokio::join!(message_handler, broadcast_handler);
-    if let Err(e) = message_handler_result {
-	println!("DEBUG: Message handler task failed: {:?}", e);
+// Add the client to a channel, start forwarding its messages and tell everyone in it
+async fn join_channel(
+    channel: &str,
+    state: &ServerState,
+    subscriptions: &mut HashMap<String, JoinHandle<()>>,
+    write_stream: &Outbound,
+    session: &SessionInfo,
+    supervisor: &Supervisor,
+) -> std::io::Result<()> {
+    let client_token = session.client_token;
+    if subscriptions.contains_key(channel) {
+        return send_error(write_stream, &format!("You are already in {}.", channel));
     }
-    
-    if let Err(e) = broadcast_handler_result {
-	println!("DEBUG: Broadcast handler task failed: {:?}", e);
+
+    // Confirm first so the client knows the channel before its first message arrives
+    write_stream.send(&Frame::Joined { channel: channel.to_string() })?;
+
+    let (subscription, history) = {
+        let mut registry = state.channels.lock().await;
+        let history = registry.history(channel);
+        (registry.join(channel, client_token, &session.username), history)
+    };
+
+    // Replay the backlog before anything new, the receiver buffers whatever arrives meanwhile
+    if !history.is_empty() && session.capabilities.iter().any(|cap| cap == CAP_HISTORY) {
+        write_stream.send(&Frame::History { channel: channel.to_string(), messages: history })?;
+    }
+
+    subscriptions.insert(channel.to_string(), spawn_forwarder(subscription, write_stream.clone(), channel.to_string(), state, supervisor));
+    debug!(channel, "joined channel");
+    state.channels.lock().await.announce_join(channel, &session.username);
+    Ok(())
+}
+
+// Remove the client from a channel and tell the remaining members
+async fn part_channel(
+    channel: &str,
+    channels: &Arc<Mutex<ChannelRegistry>>,
+    subscriptions: &mut HashMap<String, JoinHandle<()>>,
+    my_username: &str,
+    client_token: usize,
+    notice: String,
+) {
+    if let Some(forwarder) = subscriptions.remove(channel) {
+        forwarder.abort();
+    }
+
+    let mut registry = channels.lock().await;
+    if registry.part(channel, client_token) {
+        debug!(channel, "left channel");
+        registry.announce_leave(channel, my_username, notice);
+    }
+}
+
+// Leave every channel with a notice and forget the user, the last step of every session
+pub(crate) async fn finish_session(state: &ServerState, client_token: usize, my_username: &str, channels: Vec<String>) {
+    {
+        let mut registry = state.channels.lock().await;
+        for channel in channels {
+            // Nobody needs a leave notice for each user when the whole server goes down
+            if registry.part(&channel, client_token) && !state.shutdown.is_cancelled() {
+                // Each channel gets the disconnect message only once
+                registry.announce_leave(&channel, my_username, format!("{} has left the chat!", my_username));
+            }
+        }
+    }
+
+    // Nobody can reach this connection anymore
+    state.client_writers.lock().await.remove(&client_token);
+    state.sessions.lock().await.remove(client_token);
+
+    // The username is free for the next login
+    state.users.lock().await.release(client_token);
+    debug!(client_token, username = my_username, "session finished");
+}
+
+// Keep the username and channel memberships of a dropped connection for the grace period,
+// collecting what it misses so a resumed connection can catch up
+async fn detach_session(state: &ServerState, info: SessionInfo, channels: Vec<String>) {
+    let client_token = info.client_token;
+    let receivers = {
+        let registry = state.channels.lock().await;
+        channels.iter().filter_map(|channel| registry.subscribe(channel).map(|subscription| (channel.clone(), subscription.receiver))).collect()
+    };
+    state.client_writers.lock().await.remove(&client_token);
+    state.sessions.lock().await.detach(DetachedSession::new(info.clone(), receivers));
+    debug!(grace_secs = state.config().resume_grace_secs, "detached session");
+
+    let state = state.clone();
+    let grace = Duration::from_secs(state.config().resume_grace_secs);
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        // Nothing to do if the session was resumed in the meantime
+        let expired = state.sessions.lock().await.take_expired(client_token, grace);
+        if let Some(session) = expired {
+            info!("detached session expired");
+            let channels = session.channels.clone();
+            session.finish();
+            finish_session(&state, client_token, &info.username, channels).await;
+        }
+    }.in_current_span());
+}
+
+// Subscribe a resumed session to its channels again and replay what it missed, without any join notices
+async fn reattach_session(
+    resumed: DetachedSession,
+    state: &ServerState,
+    subscriptions: &mut HashMap<String, JoinHandle<()>>,
+    write_stream: &Outbound,
+    supervisor: &Supervisor,
+) -> std::io::Result<()> {
+    // Subscribe before the collectors stop so nothing falls in between
+    let subscribed: Vec<(String, Subscription)> = {
+        let registry = state.channels.lock().await;
+        resumed.channels.iter().filter_map(|channel| registry.subscribe(channel).map(|subscription| (channel.clone(), subscription))).collect()
+    };
+    let missed = resumed.finish();
+
+    for (channel, _) in &subscribed {
+        write_stream.send(&Frame::Joined { channel: channel.clone() })?;
+    }
+    debug!(missed = missed.len(), "replaying missed frames");
+    for frame in &missed {
+        write_stream.send(frame)?;
+    }
+    for (channel, subscription) in subscribed {
+        subscriptions.insert(channel.clone(), spawn_forwarder(subscription, write_stream.clone(), channel, state, supervisor));
+    }
+    Ok(())
+}
+
+pub async fn handle_client(
+    reader: BufReader<BoxedReader>,
+    write_stream: Outbound,
+    session: SessionInfo,
+    resumed: Option<DetachedSession>,
+    state: ServerState,
+) {
+    let client_token = session.client_token;
+    let my_username = session.username.clone();
+    info!(resumed = resumed.is_some(), "session started");
+
+    // Every task of the connection is stopped together, on the first reason to end it:
+    // the client, a failed write, an administrator, a resume elsewhere or the server shutdown
+    let supervisor = Supervisor::new(&state.shutdown);
+
+    // Make this connection reachable for private messages and for kicks
+    state.client_writers.lock().await.insert(client_token, write_stream.clone());
+    state.sessions.lock().await.attach(client_token, supervisor.clone());
+
+    let reason = tokio::select! {
+        reason = serve_session(reader, &write_stream, &session, resumed, &state, &supervisor) => reason,
+        reason = supervisor.stopped() => reason,
+        // The client stopped taking what is sent to it
+        reason = write_stream.failed() => DisconnectReason::WriteError(reason),
+    };
+    supervisor.stop(reason);
+    supervisor.join().await;
+
+    // Whoever stopped the connection first decides how it ends, the cleanup below runs exactly once
+    let reason = supervisor.reason();
+    info!(%reason, "connection ended");
+    state.metrics.record_disconnect(reason.kind());
+    if let DisconnectReason::Kicked(text) = &reason {
+        let _ = write_stream.send(&Frame::System { channel: None, text: text.clone(), timestamp: now_timestamp() });
+        // Clients that understand it stop reconnecting
+        if session.capabilities.iter().any(|cap| cap == CAP_DISCONNECT) {
+            let _ = write_stream.send(&Frame::Disconnect { reason: text.clone() });
+        }
+    }
+    let joined = state.channels.lock().await.channels_of(client_token);
+    if reason.ends_session() || state.config().resume_grace_secs == 0 {
+        finish_session(&state, client_token, &my_username, joined).await;
+    } else {
+        // The connection dropped, give the client a chance to resume
+        detach_session(&state, session, joined).await;
+    }
+
+    // Deliver what is still queued, such as a kick notice, then close the connection
+    write_stream.close().await;
+}
+
+// Read and answer the client's frames until the session should end, forwarding its channels meanwhile
+async fn serve_session(
+    mut reader: BufReader<BoxedReader>,
+    write_stream: &Outbound,
+    session: &SessionInfo,
+    resumed: Option<DetachedSession>,
+    state: &ServerState,
+    supervisor: &Supervisor,
+) -> DisconnectReason {
+    let client_token = session.client_token;
+    let my_username = session.username.clone();
+    let channels = Arc::clone(&state.channels);
+
+    // One forwarding task per joined channel, keyed by channel name
+    let mut subscriptions: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    match resumed {
+        // A resumed session picks up its old channels silently
+        Some(resumed) => {
+            if reattach_session(resumed, state, &mut subscriptions, write_stream, supervisor).await.is_err() {
+                warn!("failed to replay the resumed session");
+            }
+        }
+        // Everyone else starts out in the default channel
+        None => {
+            if join_channel(DEFAULT_CHANNEL, state, &mut subscriptions, write_stream, session, supervisor).await.is_err() {
+                warn!(channel = DEFAULT_CHANNEL, "failed to join the default channel");
+            }
+        }
+    }
+
+    // A PING that got no answer within the ping timeout means the connection is dead
+    let mut awaiting_pong = false;
+    loop {
+        let config = state.config();
+        let wait = Duration::from_secs(if awaiting_pong { config.ping_timeout_secs } else { config.ping_interval_secs });
+        match timeout(wait, read_frame(&mut reader, MAX_FRAME_LEN)).await {
+            Ok(Ok(Some(frame))) => {
+                awaiting_pong = false;
+                state.metrics.record_command(frame.kind());
+                // Text meant for other users loses its terminal control sequences first
+                let Some(frame) = sanitize_frame(frame, config.sanitize) else {
+                    debug!("rejected a message with control characters");
+                    if let Err(e) = send_error(write_stream, REJECTED_MESSAGE) {
+                        return DisconnectReason::WriteError(e.to_string());
+                    }
+                    continue;
+                };
+                let result = match frame {
+                    Frame::Quit => return DisconnectReason::Quit,
+                    Frame::Pong => {
+                        debug!("received pong");
+                        Ok(())
+                    }
+                    Frame::GetUserList { channel } => {
+                        let users = channels.lock().await.members(&channel);
+                        write_stream.send(&Frame::UserList { channel, users })
+                    }
+                    Frame::Join { channel } => match normalize_channel_name(&channel) {
+                        Some(channel) => join_channel(&channel, state, &mut subscriptions, write_stream, session, supervisor).await,
+                        None => send_error(write_stream, &format!("Invalid channel name '{}'.", channel)),
+                    },
+                    Frame::Part { channel } => {
+                        let channel = normalize_channel_name(&channel).unwrap_or(channel);
+                        if subscriptions.contains_key(&channel) {
+                            let notice = format!("{} has left {}.", my_username, channel);
+                            part_channel(&channel, &channels, &mut subscriptions, &my_username, client_token, notice).await;
+                            write_stream.send(&Frame::Parted { channel })
+                        } else {
+                            send_error(write_stream, &format!("You are not in {}.", channel))
+                        }
+                    }
+                    Frame::PrivateMessage { to, text } => {
+                        send_direct_message(&to, text, write_stream, &my_username, client_token, state).await
+                    }
+                    Frame::Register { password } => {
+                        register_account(password, write_stream, &my_username, state).await
+                    }
+                    Frame::Say { channel, text } => {
+                        let text = text.trim().to_string();
+                        let mut registry = channels.lock().await;
+                        if text.is_empty() {
+                            Ok(())
+                        } else if !registry.is_member(&channel, client_token) {
+                            drop(registry);
+                            send_error(write_stream, &format!("You are not in {}.", channel))
+                        } else {
+                            // Broadcast the message only once, to the channel it was written in
+                            let message = Frame::Chat { channel: channel.clone(), from: my_username.clone(), text, timestamp: now_timestamp() };
+                            debug!(?message, "broadcasting message");
+                            if let Some(store) = &state.store {
+                                store.record(&message);
+                            }
+                            registry.send(&channel, message.clone());
+                            drop(registry);
+                            increment(&state.metrics.channel_messages);
+                            // Mentioned users who are offline find the line in their mailbox
+                            if let Frame::Chat { text, .. } = &message {
+                                for username in mentioned_usernames(text) {
+                                    if username != my_username {
+                                        queue_offline(state, &username, &message).await;
+                                    }
+                                }
+                            }
+                            Ok(())
+                        }
+                    }
+                    other => {
+                        warn!(frame = ?other, "unexpected frame");
+                        send_error(write_stream, "Unexpected frame.")
+                    }
+                };
+                if let Err(e) = result {
+                    return DisconnectReason::WriteError(e.to_string());
+                }
+            }
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+                // The line arrived but was not a valid frame, tell the client and keep going
+                warn!(error = %e, "malformed frame");
+                if let Err(e) = send_error(write_stream, "Malformed frame.") {
+                    return DisconnectReason::WriteError(e.to_string());
+                }
+            }
+            Ok(Ok(None)) | Ok(Err(_)) => return DisconnectReason::ConnectionLost,
+            Err(_) if awaiting_pong => {
+                increment(&state.metrics.ping_timeouts);
+                return DisconnectReason::PingTimeout;
+            }
+            Err(_) => {
+                debug!("idle, sending ping");
+                if let Err(e) = write_stream.send(&Frame::Ping) {
+                    return DisconnectReason::WriteError(e.to_string());
+                }
+                awaiting_pong = true;
+            }
+        }
     }
-    
-    println!("DEBUG: Exiting handle_client for client {}", client_token);
 }
@@ -1,11 +1,243 @@
-use std::io::Write;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use std::fs::File;
-
-pub async fn log_message(log_file: &Arc<Mutex<File>>, message: &str) {
-    let mut file = log_file.lock().await;
-    if writeln!(&mut *file, "{}", message).is_err() {
-        eprintln!("Failed to write to log file: {}", message);
+use chrono::{DateTime, Local, NaiveDate};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{OnceLock, mpsc};
+use std::thread;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::{Layer, Registry, fmt, reload};
+use crate::config::{LogFormat, LogLevel, LogRotation, LogSettings};
+
+type LevelLayer = reload::Layer<LevelFilter, Registry>;
+
+// Work for the thread that owns the log file
+enum LogCommand {
+    Write(Vec<u8>),
+    Reopen(LogSettings, mpsc::Sender<io::Result<()>>),
+    Flush(mpsc::Sender<()>), // answered once everything queued before it is on disk
+}
+
+// The installed subscriber: the level can be changed while it runs, the format is fixed
+struct Logger {
+    sender: mpsc::Sender<LogCommand>,
+    level: reload::Handle<LevelFilter, Registry>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// Send every tracing event of the process to the configured log file. Lines are written by a
+// background thread, so logging from a connection task never waits for the disk.
+pub fn init(settings: &LogSettings) -> io::Result<()> {
+    let file = LogFile::open(settings.clone())?;
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new().name("log-writer".to_string()).spawn(move || run_writer(file, receiver))?;
+
+    let (level, handle) = reload::Layer::new(level_filter(settings.level));
+    let subscriber = Registry::default().with(level).with(output_layer(settings.format, sender.clone()));
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| io::Error::other(e.to_string()))?;
+    let _ = LOGGER.set(Logger { sender, level: handle });
+    Ok(())
+}
+
+// Apply new settings to the running logger: level, file and rotation. The format only changes on restart,
+// events of spans that were opened before would not have their fields in the new format.
+pub fn reconfigure(settings: &LogSettings) -> io::Result<()> {
+    let Some(logger) = LOGGER.get() else {
+        return Ok(());
+    };
+    let (done, wait) = mpsc::channel();
+    logger.sender.send(LogCommand::Reopen(settings.clone(), done)).map_err(|_| io::Error::other("the log writer has stopped"))?;
+    wait.recv().map_err(|_| io::Error::other("the log writer has stopped"))??;
+    logger.level.reload(level_filter(settings.level)).map_err(|e| io::Error::other(e.to_string()))
+}
+
+// Block until every line logged so far is on disk
+pub fn flush() {
+    if let Some(logger) = LOGGER.get() {
+        let (done, wait) = mpsc::channel();
+        if logger.sender.send(LogCommand::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+fn level_filter(level: LogLevel) -> LevelFilter {
+    match level {
+        LogLevel::Error => LevelFilter::ERROR,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Debug => LevelFilter::DEBUG,
+    }
+}
+
+fn output_layer(format: LogFormat, sender: mpsc::Sender<LogCommand>) -> Box<dyn Layer<Layered<LevelLayer, Registry>> + Send + Sync> {
+    let writer = move || EventWriter { buffer: Vec::new(), sender: sender.clone() };
+    match format {
+        LogFormat::Text => fmt::layer().with_ansi(false).with_target(false).with_writer(writer).boxed(),
+        // The fields of the connection span (token, peer, username) go next to the event fields
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_target(false)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+// Collects one formatted event and hands it to the writer thread when dropped
+struct EventWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<LogCommand>,
+}
+
+impl Write for EventWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let _ = self.sender.send(LogCommand::Write(std::mem::take(&mut self.buffer)));
+        }
+    }
+}
+
+fn run_writer(mut file: LogFile, receiver: mpsc::Receiver<LogCommand>) {
+    // The logger is static, so this only ends with the process
+    while let Ok(command) = receiver.recv() {
+        match command {
+            LogCommand::Write(line) => {
+                if let Err(e) = file.write(&line) {
+                    eprintln!("Failed to write to log file {}: {}", file.path.display(), e);
+                }
+            }
+            LogCommand::Reopen(settings, done) => {
+                let result = if settings.file == file.settings.file {
+                    // Same file, only the rotation settings may have changed
+                    file.settings = settings;
+                    file.rotate_if_needed(0)
+                } else {
+                    LogFile::open(settings).map(|reopened| file = reopened)
+                };
+                let _ = done.send(result);
+            }
+            LogCommand::Flush(done) => {
+                let _ = file.file.flush().and_then(|_| file.file.sync_all());
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+// The current log file, moved aside to "<file>.<date>" each day or to "<file>.1", "<file>.2", ...
+// once it reaches the size limit
+struct LogFile {
+    settings: LogSettings,
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl LogFile {
+    fn open(settings: LogSettings) -> io::Result<Self> {
+        let path = PathBuf::from(&settings.file);
+        let today = Local::now().date_naive();
+        let (size, modified_on) = match fs::metadata(&path) {
+            Ok(metadata) => (metadata.len(), metadata.modified().ok().map(|time| DateTime::<Local>::from(time).date_naive())),
+            Err(_) => (0, None),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut log = LogFile { settings, path, file, size, opened_on: modified_on.unwrap_or(today) };
+        // A log left over from an earlier day or beyond the size limit is rotated right away
+        log.rotate_if_needed(0)?;
+        Ok(log)
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        self.rotate_if_needed(line.len() as u64)?;
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate_if_needed(&mut self, incoming: u64) -> io::Result<()> {
+        let today = Local::now().date_naive();
+        let rotated = match self.settings.rotation {
+            LogRotation::Never => return Ok(()),
+            LogRotation::Daily if self.opened_on == today || self.size == 0 => {
+                self.opened_on = today;
+                return Ok(());
+            }
+            LogRotation::Daily => self.rotated_path(&self.opened_on.format("%Y-%m-%d").to_string()),
+            LogRotation::Size if self.size == 0 || self.size + incoming <= self.settings.max_size_mb * 1024 * 1024 => return Ok(()),
+            LogRotation::Size => {
+                // Make room for the new "<file>.1"
+                let mut highest = 0;
+                while self.rotated_path(&(highest + 1).to_string()).exists() {
+                    highest += 1;
+                }
+                for number in (1..=highest).rev() {
+                    fs::rename(self.rotated_path(&number.to_string()), self.rotated_path(&(number + 1).to_string()))?;
+                }
+                self.rotated_path("1")
+            }
+        };
+        self.file.flush()?;
+        fs::rename(&self.path, &rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_on = today;
+        self.prune()
+    }
+
+    fn rotated_path(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".");
+        path.push(suffix);
+        path.into()
+    }
+
+    // Delete the oldest rotated logs beyond the configured number
+    fn prune(&self) -> io::Result<()> {
+        if self.settings.max_files == 0 {
+            return Ok(());
+        }
+        let Some(name) = self.path.file_name().and_then(|name| name.to_str()) else {
+            return Ok(());
+        };
+        let prefix = format!("{}.", name);
+        let directory = match self.path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let mut rotated = Vec::new();
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(suffix) = file_name.to_str().and_then(|file_name| file_name.strip_prefix(&prefix)) else {
+                continue;
+            };
+            // Only dates and numbers, never files that merely share the name
+            if suffix.is_empty() || !suffix.chars().all(|c| c.is_ascii_digit() || c == '-') {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            rotated.push((modified, entry.path()));
+        }
+        rotated.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+        for (_, path) in rotated.into_iter().skip(self.settings.max_files) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
     }
 }
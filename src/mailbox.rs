@@ -0,0 +1,70 @@
+use std::collections::{HashMap, VecDeque};
+use crate::protocol::Frame;
+use crate::users::{look_alike, username_keys};
+
+// Messages kept per offline user, older ones are dropped first
+const MAX_OFFLINE_MESSAGES: usize = 100;
+
+// Mentions and private messages for users who are not connected, handed over the next time
+// they log in. Usernames are matched by the keys of the user registry, so a message for
+// "alice" reaches "Alice".
+#[derive(Default)]
+pub struct OfflineMailbox {
+    known: HashMap<String, String>, // both keys of the usernames that logged in since the server started -> username as last used
+    queued: HashMap<String, VecDeque<Frame>>, // first key of the username -> frames
+}
+
+impl OfflineMailbox {
+    pub fn new() -> Self {
+        OfflineMailbox::default()
+    }
+
+    // Remember a username that just logged in
+    pub fn remember(&mut self, username: &str) {
+        for key in username_keys(username) {
+            self.known.insert(key, username.to_string());
+        }
+    }
+
+    // The spelling of a known username that this one looks like
+    pub fn known_as(&self, username: &str) -> Option<&str> {
+        username_keys(username).iter().find_map(|key| self.known.get(key)).map(String::as_str)
+    }
+
+    // Queue a frame for the user, keeping its original sender and timestamp
+    pub fn push(&mut self, username: &str, frame: Frame) {
+        let [key, _] = username_keys(username);
+        let queue = self.queued.entry(key).or_default();
+        if queue.len() >= MAX_OFFLINE_MESSAGES {
+            queue.pop_front();
+        }
+        queue.push_back(frame);
+    }
+
+    // Everything queued for the user under any spelling, oldest first
+    pub fn take(&mut self, username: &str) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = username_keys(username).iter().filter_map(|key| self.queued.remove(key)).flatten().collect();
+        // Both keys may have a queue when the user was addressed by lookalikes of either kind
+        frames.sort_by_key(|frame| match frame {
+            Frame::Chat { timestamp, .. } | Frame::Direct { timestamp, .. } | Frame::System { timestamp, .. } => *timestamp,
+            _ => 0,
+        });
+        frames
+    }
+}
+
+// Usernames mentioned as @name in a chat line, without @all and names mentioned before in another spelling
+pub fn mentioned_usernames(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for word in text.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        // Allow punctuation after the name, as in "thanks @alice!"
+        let name = name.trim_end_matches(|c: char| c.is_ascii_punctuation() && c != '_' && c != '-');
+        if !name.is_empty() && name != "all" && !names.iter().any(|n| look_alike(n, name)) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::tls::{BoxedReader, BoxedWriter};
+
+// Content type of the Prometheus text exposition format
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// Counters of the whole server, updated by the connection tasks and served on /metrics
+#[derive(Default)]
+pub struct Metrics {
+    pub connected_clients: AtomicUsize, // open connections, including the ones still logging in
+    pub connections_accepted: AtomicU64,
+    pub connections_rejected: AtomicU64, // turned away because the server was full
+    pub channel_messages: AtomicU64,
+    pub private_messages: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub ping_timeouts: AtomicU64,
+    pub username_collisions: AtomicU64,
+    pub broadcast_lag_events: AtomicU64, // a client fell behind its channel and lost frames
+    pub broadcast_lagged_frames: AtomicU64,
+    pub outbound_dropped_frames: AtomicU64, // dropped from full outbound queues
+    pub slow_consumer_disconnects: AtomicU64,
+    pub write_timeouts: AtomicU64,
+    commands: LabelledCounter, // frames received from clients, by type
+    disconnects: LabelledCounter, // ended connections, by reason
+}
+
+// A counter per label value, such as per frame type
+#[derive(Default)]
+struct LabelledCounter(Mutex<HashMap<&'static str, u64>>);
+
+impl LabelledCounter {
+    fn increment(&self, label: &'static str) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()).entry(label).or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let counts = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort();
+        for (value, count) in counts {
+            let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count);
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_command(&self, command: &'static str) {
+        self.commands.increment(command);
+    }
+
+    pub fn record_disconnect(&self, reason: &'static str) {
+        self.disconnects.increment(reason);
+    }
+
+    pub fn record_lag(&self, skipped: u64) {
+        increment(&self.broadcast_lag_events);
+        self.broadcast_lagged_frames.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    // Everything in the Prometheus text format, with the gauges only the caller knows
+    pub fn render(&self, users: usize, uptime: Duration) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        metric(&mut out, "termtalk_uptime_seconds", "gauge", "Time since the server started.", uptime.as_secs());
+        metric(&mut out, "termtalk_connected_clients", "gauge", "Open client connections.", self.connected_clients.load(Ordering::Relaxed) as u64);
+        metric(&mut out, "termtalk_users", "gauge", "Logged in users, detached sessions included.", users as u64);
+        metric(&mut out, "termtalk_connections_accepted_total", "counter", "Connections accepted.", load(&self.connections_accepted));
+        metric(&mut out, "termtalk_connections_rejected_total", "counter", "Connections turned away because the server was full.", load(&self.connections_rejected));
+
+        header(&mut out, "termtalk_messages_relayed_total", "counter", "Chat messages relayed, by kind.");
+        let _ = writeln!(out, "termtalk_messages_relayed_total{{kind=\"channel\"}} {}", load(&self.channel_messages));
+        let _ = writeln!(out, "termtalk_messages_relayed_total{{kind=\"private\"}} {}", load(&self.private_messages));
+
+        metric(&mut out, "termtalk_received_bytes_total", "counter", "Bytes received from clients.", load(&self.bytes_received));
+        metric(&mut out, "termtalk_sent_bytes_total", "counter", "Bytes sent to clients.", load(&self.bytes_sent));
+        metric(&mut out, "termtalk_ping_timeouts_total", "counter", "Clients disconnected for not answering a ping.", load(&self.ping_timeouts));
+        metric(&mut out, "termtalk_username_collisions_total", "counter", "Logins refused because the username was in use.", load(&self.username_collisions));
+        metric(&mut out, "termtalk_broadcast_lag_events_total", "counter", "Times a client fell behind the broadcast of a channel.", load(&self.broadcast_lag_events));
+        metric(&mut out, "termtalk_broadcast_lagged_frames_total", "counter", "Frames skipped by clients that fell behind.", load(&self.broadcast_lagged_frames));
+        metric(&mut out, "termtalk_outbound_dropped_frames_total", "counter", "Frames dropped because the outbound queue of a client was full.", load(&self.outbound_dropped_frames));
+        metric(&mut out, "termtalk_slow_consumer_disconnects_total", "counter", "Clients disconnected because their outbound queue was full.", load(&self.slow_consumer_disconnects));
+        metric(&mut out, "termtalk_write_timeouts_total", "counter", "Clients disconnected because a write timed out.", load(&self.write_timeouts));
+
+        header(&mut out, "termtalk_commands_total", "counter", "Frames received from clients, by type.");
+        self.commands.render(&mut out, "termtalk_commands_total", "command");
+        header(&mut out, "termtalk_disconnects_total", "counter", "Ended client sessions and connections, by reason.");
+        self.disconnects.render(&mut out, "termtalk_disconnects_total", "reason");
+        out
+    }
+}
+
+pub fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Wrap both halves of a connection so every byte read or written is counted
+pub fn count_traffic(metrics: &Arc<Metrics>, reader: BoxedReader, writer: BoxedWriter) -> (BoxedReader, BoxedWriter) {
+    (
+        Box::new(CountedReader { inner: reader, metrics: Arc::clone(metrics) }),
+        Box::new(CountedWriter { inner: writer, metrics: Arc::clone(metrics) }),
+    )
+}
+
+struct CountedReader {
+    inner: BoxedReader,
+    metrics: Arc<Metrics>,
+}
+
+impl AsyncRead for CountedReader {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.metrics.bytes_received.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+struct CountedWriter {
+    inner: BoxedWriter,
+    metrics: Arc<Metrics>,
+}
+
+impl AsyncWrite for CountedWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.metrics.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug};
+use crate::channels::Event;
+use crate::config::{OutboundSettings, OverflowPolicy};
+use crate::metrics::{Metrics, increment};
+use crate::protocol::{Frame, encode, now_timestamp};
+use crate::tls::BoxedWriter;
+
+// Lines handed to one vectored write, the usual limit of the system call is 1024
+const MAX_WRITE_SLICES: usize = 64;
+
+struct Queue {
+    lines: VecDeque<Arc<str>>, // encoded frames waiting for the writer task, oldest first
+    dropped: u64, // frames dropped since the client was last told about it
+    closing: bool, // nothing more is queued, write the rest and shut the stream down
+    failure: Option<String>, // why the connection was given up
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    settings: OutboundSettings,
+    metrics: Arc<Metrics>,
+    wake: Notify, // something was queued or the queue was closed
+    failed: CancellationToken,
+    finished: CancellationToken, // the writer task is done with the stream
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closing = true;
+        self.wake.notify_one();
+    }
+
+    // Give up on the connection, whatever is still queued is lost
+    fn fail(&self, reason: String) {
+        let mut queue = self.lock();
+        queue.lines.clear();
+        queue.failure.get_or_insert(reason);
+        self.failed.cancel();
+    }
+}
+
+// Closes the queue once the last handle is gone, the writer then finishes like a dropped socket would
+struct CloseOnDrop(Arc<Shared>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// The sending side of a client connection. Frames are queued without waiting and written by a
+// single task per connection, so a client that stops reading holds up nobody but itself.
+#[derive(Clone)]
+pub struct Outbound {
+    shared: Arc<Shared>,
+    _close_on_drop: Arc<CloseOnDrop>,
+}
+
+impl Outbound {
+    // Start the writer task of a connection
+    pub fn spawn(writer: BoxedWriter, settings: OutboundSettings, metrics: Arc<Metrics>) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue { lines: VecDeque::new(), dropped: 0, closing: false, failure: None }),
+            settings,
+            metrics,
+            wake: Notify::new(),
+            failed: CancellationToken::new(),
+            finished: CancellationToken::new(),
+        });
+        tokio::spawn(run_writer(writer, Arc::clone(&shared)).in_current_span());
+        Outbound { _close_on_drop: Arc::new(CloseOnDrop(Arc::clone(&shared))), shared }
+    }
+
+    // Queue a frame for the client, fails once the connection is closed or given up.
+    // A full queue is handled by the overflow policy.
+    pub fn send(&self, frame: &Frame) -> std::io::Result<()> {
+        self.send_line(encode(frame).into())
+    }
+
+    // Queue a frame broadcast to a channel, its encoded line is shared rather than copied
+    pub fn send_event(&self, event: &Event) -> std::io::Result<()> {
+        self.send_line(Arc::clone(&event.line))
+    }
+
+    // Queue a frame that was already encoded, for the same frame going to many clients
+    pub fn send_line(&self, line: Arc<str>) -> std::io::Result<()> {
+        let mut queue = self.shared.lock();
+        if queue.closing || queue.failure.is_some() {
+            return Err(Error::new(ErrorKind::BrokenPipe, "the connection is closed"));
+        }
+        if queue.lines.len() >= self.shared.settings.queue_size {
+            match self.shared.settings.overflow {
+                OverflowPolicy::DropOldest => {
+                    queue.lines.pop_front();
+                    queue.dropped += 1;
+                    increment(&self.shared.metrics.outbound_dropped_frames);
+                }
+                OverflowPolicy::Disconnect => {
+                    drop(queue);
+                    increment(&self.shared.metrics.slow_consumer_disconnects);
+                    self.shared.fail("too slow to keep up".to_string());
+                    return Err(Error::new(ErrorKind::BrokenPipe, "the client is too slow to keep up"));
+                }
+            }
+        }
+        queue.lines.push_back(line);
+        drop(queue);
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    // Resolves with the reason once the connection was given up: a write failed or timed out,
+    // or the client fell too far behind
+    pub async fn failed(&self) -> String {
+        self.shared.failed.cancelled().await;
+        self.shared.lock().failure.clone().unwrap_or_default()
+    }
+
+    // Write what is still queued, shut the stream down and wait for the writer task to finish
+    pub async fn close(&self) {
+        self.shared.close();
+        self.shared.finished.cancelled().await;
+    }
+}
+
+// Write the lines as they are, broadcast lines are shared with the other clients and not copied
+async fn write_lines(writer: &mut BoxedWriter, lines: &[Arc<str>]) -> std::io::Result<()> {
+    let (mut line, mut offset) = (0, 0);
+    while line < lines.len() {
+        let slices: Vec<IoSlice<'_>> = std::iter::once(&lines[line].as_bytes()[offset..])
+            .chain(lines[line + 1..].iter().map(|rest| rest.as_bytes()))
+            .take(MAX_WRITE_SLICES)
+            .map(IoSlice::new)
+            .collect();
+        let mut written = writer.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        // Skip what was written, possibly stopping in the middle of a line
+        while line < lines.len() && written >= lines[line].len() - offset {
+            written -= lines[line].len() - offset;
+            line += 1;
+            offset = 0;
+        }
+        offset += written;
+    }
+    writer.flush().await
+}
+
+async fn run_writer(mut writer: BoxedWriter, shared: Arc<Shared>) {
+    loop {
+        let (batch, closing) = {
+            let mut queue = shared.lock();
+            let mut batch: Vec<Arc<str>> = Vec::with_capacity(queue.lines.len() + 1);
+            if queue.dropped > 0 {
+                let unit = if queue.dropped == 1 { "message" } else { "messages" };
+                let text = format!("Your connection is too slow, {} {} could not be delivered.", queue.dropped, unit);
+                batch.push(encode(&Frame::System { channel: None, text, timestamp: now_timestamp() }).into());
+                queue.dropped = 0;
+            }
+            batch.extend(queue.lines.drain(..));
+            (batch, queue.closing)
+        };
+        if batch.is_empty() {
+            if closing {
+                break;
+            }
+            tokio::select! {
+                _ = shared.wake.notified() => continue,
+                _ = shared.failed.cancelled() => break,
+            }
+        }
+
+        let result = tokio::select! {
+            result = timeout(shared.settings.write_timeout, write_lines(&mut writer, &batch)) => result,
+            _ = shared.failed.cancelled() => break,
+        };
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                shared.fail(format!("write failed: {}", e));
+                break;
+            }
+            Err(_) => {
+                increment(&shared.metrics.write_timeouts);
+                shared.fail("write timed out".to_string());
+                break;
+            }
+        }
+    }
+
+    if shared.failed.is_cancelled() {
+        debug!(reason = %shared.lock().failure.clone().unwrap_or_default(), "stopped writing to the client");
+    } else {
+        // Close cleanly, with a TLS close_notify where TLS is used
+        let _ = timeout(shared.settings.write_timeout, writer.shutdown()).await;
+    }
+    shared.finished.cancel();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::AsyncWrite;
+
+    // Takes at most a few bytes per write, spread over as many slices as they come from
+    struct Trickle {
+        written: Arc<Mutex<Vec<u8>>>,
+        per_write: usize,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(self: Pin<&mut Self>, _: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<std::io::Result<usize>> {
+            let mut written = self.written.lock().unwrap();
+            let mut left = self.per_write;
+            for buf in bufs {
+                let n = buf.len().min(left);
+                written.extend_from_slice(&buf[..n]);
+                left -= n;
+            }
+            Poll::Ready(Ok(self.per_write - left))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_every_line_across_partial_writes() {
+        let lines: Vec<Arc<str>> = (0..200).map(|i| Arc::from(format!("line {}\n", "x".repeat(i % 13)))).collect();
+        let expected: String = lines.iter().map(|line| &**line).collect();
+        for per_write in [1, 3, 7, 64, 100_000] {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let mut writer: BoxedWriter = Box::new(Trickle { written: Arc::clone(&written), per_write });
+            write_lines(&mut writer, &lines).await.unwrap();
+            assert_eq!(String::from_utf8(written.lock().unwrap().clone()).unwrap(), expected, "{} bytes per write", per_write);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::marker::Unpin;
+use std::sync::Arc;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use crate::utils::write_to_stream;
+
+// Bumped whenever a frame changes in a way older peers cannot understand
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Longest line the server reads from a client, far more than anyone types into one message
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+// Longest line a client reads from the server, history and offline messages bundle many messages
+pub const MAX_SERVER_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+// Optional features a peer can advertise during the handshake.
+// Unknown names from newer peers are ignored rather than rejected.
+pub const CAP_USER_LIST: &str = "user_list";
+pub const CAP_CHANNELS: &str = "channels";
+pub const CAP_DIRECT_MESSAGES: &str = "direct_messages";
+pub const CAP_ACCOUNTS: &str = "accounts";
+pub const CAP_RESUME: &str = "resume";
+pub const CAP_HISTORY: &str = "history";
+pub const CAP_OFFLINE_MESSAGES: &str = "offline_messages";
+pub const CAP_DISCONNECT: &str = "disconnect";
+
+pub const SUPPORTED_CAPABILITIES: &[&str] = &[
+    CAP_USER_LIST,
+    CAP_CHANNELS,
+    CAP_DIRECT_MESSAGES,
+    CAP_ACCOUNTS,
+    CAP_RESUME,
+    CAP_HISTORY,
+    CAP_OFFLINE_MESSAGES,
+    CAP_DISCONNECT,
+];
+
+// Seconds since the Unix epoch, the server stamps every relayed message with it
+pub fn now_timestamp() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+// Name and version of this build, sent in the handshake for diagnostics
+pub fn software_version() -> String {
+    format!("termtalk {}", env!("CARGO_PKG_VERSION"))
+}
+
+// Keep only the capabilities offered by the peer that this build also supports
+pub fn negotiate_capabilities(offered: &[String]) -> Vec<String> {
+    SUPPORTED_CAPABILITIES
+        .iter()
+        .filter(|cap| offered.iter().any(|o| o == *cap))
+        .map(|cap| cap.to_string())
+        .collect()
+}
+
+// Every frame travels as a single line of JSON terminated by '\n'.
+// The "type" field selects the variant, e.g. {"type":"chat","from":"bob","text":"hi"}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    // Handshake: the client opens with a hello, the server answers with a welcome
+    // (or an error if the versions do not match), then the client logs in
+    Hello { version: u32, software: String, capabilities: Vec<String> },
+    Welcome { version: u32, software: String, capabilities: Vec<String>, token: String },
+    // The password is only needed for registered usernames, guests leave it out
+    Login {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    // Instead of a login, a reconnecting client presents the token of its previous connection
+    Resume { token: String },
+    LoginAccepted {
+        username: String,
+        #[serde(default)]
+        registered: bool,
+        #[serde(default)]
+        resumed: bool,
+    },
+    // Client -> server: /register, claims the current guest username with a password
+    Register { password: String },
+
+    // Client -> server: a chat line typed by the user into a channel
+    Say { channel: String, text: String },
+    // Server -> client: a chat line relayed from a user in a channel.
+    // Timestamps are Unix seconds set by the server, 0 means unknown.
+    Chat {
+        channel: String,
+        from: String,
+        text: String,
+        #[serde(default)]
+        timestamp: i64,
+    },
+    // Client -> server: /msg, routed only to the target user
+    PrivateMessage { to: String, text: String },
+    // Server -> client: a private message, delivered to the target and echoed to the sender
+    Direct {
+        from: String,
+        to: String,
+        text: String,
+        #[serde(default)]
+        timestamp: i64,
+    },
+    // Server -> client: join/leave and other server notices, optionally scoped to a channel
+    System {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        text: String,
+        #[serde(default)]
+        timestamp: i64,
+    },
+    // Server -> client: the last messages of a channel, replayed on join as backlog
+    History { channel: String, messages: Vec<Frame> },
+    // Server -> client: the connection is closed on purpose, for example by a kick; the client should not reconnect
+    Disconnect { reason: String },
+    // Server -> client: mentions and private messages sent while the user was offline, right after login
+    OfflineMessages { messages: Vec<Frame> },
+
+    // Client -> server: /join and /part, confirmed by the server with joined/parted
+    Join { channel: String },
+    Part { channel: String },
+    Joined { channel: String },
+    Parted { channel: String },
+
+    GetUserList { channel: String },
+    UserList { channel: String, users: Vec<String> },
+
+    Ping,
+    Pong,
+
+    // Client -> server: the user left on purpose, do not keep the session for a resume
+    Quit,
+
+    Error { message: String },
+}
+
+impl Frame {
+    // The "type" tag of the frame as it appears on the wire
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Frame::Hello { .. } => "hello",
+            Frame::Welcome { .. } => "welcome",
+            Frame::Login { .. } => "login",
+            Frame::Resume { .. } => "resume",
+            Frame::LoginAccepted { .. } => "login_accepted",
+            Frame::Register { .. } => "register",
+            Frame::Say { .. } => "say",
+            Frame::Chat { .. } => "chat",
+            Frame::PrivateMessage { .. } => "private_message",
+            Frame::Direct { .. } => "direct",
+            Frame::System { .. } => "system",
+            Frame::History { .. } => "history",
+            Frame::Disconnect { .. } => "disconnect",
+            Frame::OfflineMessages { .. } => "offline_messages",
+            Frame::Join { .. } => "join",
+            Frame::Part { .. } => "part",
+            Frame::Joined { .. } => "joined",
+            Frame::Parted { .. } => "parted",
+            Frame::GetUserList { .. } => "get_user_list",
+            Frame::UserList { .. } => "user_list",
+            Frame::Ping => "ping",
+            Frame::Pong => "pong",
+            Frame::Quit => "quit",
+            Frame::Error { .. } => "error",
+        }
+    }
+}
+
+// Serialize a frame into a newline-terminated JSON line
+pub fn encode(frame: &Frame) -> String {
+    // Serializing a plain enum of strings cannot fail
+    let mut line = serde_json::to_string(frame).expect("frame serialization failed");
+    line.push('\n');
+    line
+}
+
+// Parse a single line (with or without the trailing newline) into a frame
+pub fn decode(line: &str) -> std::io::Result<Frame> {
+    serde_json::from_str(line.trim_end()).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+// Read the next frame of at most max_len bytes from the stream, returns Ok(None) when the peer
+// closed the connection. A longer line is skipped and reported as invalid data, so a peer cannot
+// make us buffer without limit.
+pub async fn read_frame(reader: &mut (impl AsyncBufRead + Unpin), max_len: usize) -> std::io::Result<Option<Frame>> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if (&mut *reader).take(max_len as u64).read_until(b'\n', &mut line).await? == 0 {
+            return Ok(None);
+        }
+        if line.len() == max_len && line.last() != Some(&b'\n') {
+            skip_line(reader).await?;
+            return Err(Error::new(ErrorKind::InvalidData, format!("frame longer than {} bytes", max_len)));
+        }
+        let line = std::str::from_utf8(&line).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        // Skip blank lines instead of treating them as malformed frames
+        if !line.trim().is_empty() {
+            return decode(line).map(Some);
+        }
+    }
+}
+
+// Throw away the rest of the current line without keeping it
+async fn skip_line(reader: &mut (impl AsyncBufRead + Unpin)) -> std::io::Result<()> {
+    loop {
+        let buffer = reader.fill_buf().await?;
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        match buffer.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buffer.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+// Encode a frame and write it to a shared stream
+pub async fn write_frame(write_stream: &Arc<Mutex<impl AsyncWriteExt + Unpin>>, frame: &Frame) -> std::io::Result<()> {
+    write_to_stream(write_stream, &encode(frame)).await
+}
@@ -0,0 +1,138 @@
+use std::borrow::Cow;
+use crate::config::SanitizePolicy;
+use crate::protocol::Frame;
+
+// Sent back to the author when the policy rejects a message
+pub const REJECTED_MESSAGE: &str = "Your message contains terminal control characters and was not sent.";
+
+// Characters that reorder the text around them, a line can then read differently than it was written
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// NUL, escape, line breaks and the other C0 and C1 controls, and the bidi controls
+fn is_unsafe(c: char) -> bool {
+    c.is_control() || is_bidi_control(c)
+}
+
+// Make text from a client safe to show in other terminals and to write to the logs, or None if
+// the policy rejects it. Stripping removes escape sequences as a whole and turns tabs and line
+// breaks into spaces; escaping shows every unsafe character as a visible escape such as \u{1b}.
+pub fn sanitize_text(text: &str, policy: SanitizePolicy) -> Option<Cow<'_, str>> {
+    if !text.chars().any(is_unsafe) {
+        return Some(Cow::Borrowed(text));
+    }
+    match policy {
+        SanitizePolicy::Strip => {
+            // The 8-bit forms of CSI, OSC, DCS and ST are spelled out so their sequences go as a whole too
+            let spaced = text
+                .replace(['\t', '\n', '\r'], " ")
+                .replace('\u{9b}', "\x1b[")
+                .replace('\u{9d}', "\x1b]")
+                .replace('\u{90}', "\x1bP")
+                .replace('\u{9c}', "\x1b\\");
+            let stripped = strip_ansi_escapes::strip(&spaced).unwrap_or_else(|_| spaced.clone().into_bytes());
+            let cleaned: String = String::from_utf8_lossy(&stripped).chars().filter(|c| !is_unsafe(*c)).collect();
+            Some(Cow::Owned(cleaned))
+        }
+        SanitizePolicy::Escape => {
+            let mut escaped = String::with_capacity(text.len());
+            for c in text.chars() {
+                if is_unsafe(c) {
+                    escaped.extend(c.escape_default());
+                } else {
+                    escaped.push(c);
+                }
+            }
+            Some(Cow::Owned(escaped))
+        }
+        SanitizePolicy::Reject => None,
+    }
+}
+
+// Sanitize the text of the frames a client sends to other users, the others pass unchanged
+pub fn sanitize_frame(frame: Frame, policy: SanitizePolicy) -> Option<Frame> {
+    match frame {
+        Frame::Say { channel, text } => {
+            let text = sanitize_text(&text, policy)?.into_owned();
+            Some(Frame::Say { channel, text })
+        }
+        Frame::PrivateMessage { to, text } => {
+            let text = sanitize_text(&text, policy)?.into_owned();
+            Some(Frame::PrivateMessage { to, text })
+        }
+        other => Some(other),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(text: &str) -> String {
+        sanitize_text(text, SanitizePolicy::Strip).unwrap().into_owned()
+    }
+
+    fn escape(text: &str) -> String {
+        sanitize_text(text, SanitizePolicy::Escape).unwrap().into_owned()
+    }
+
+    #[test]
+    fn clean_text_is_borrowed() {
+        for policy in [SanitizePolicy::Strip, SanitizePolicy::Escape, SanitizePolicy::Reject] {
+            let text = "héllo wörld, 你好 👋";
+            assert!(matches!(sanitize_text(text, policy), Some(Cow::Borrowed(t)) if t == text));
+        }
+    }
+
+    #[test]
+    fn strips_csi_sequences() {
+        assert_eq!(strip("\x1b[31mred\x1b[0m"), "red");
+        assert_eq!(strip("\x1b[2J\x1b[Hclear"), "clear");
+        assert_eq!(strip("a\u{9b}31mb"), "ab");
+    }
+
+    #[test]
+    fn strips_osc_sequences() {
+        assert_eq!(strip("\x1b]0;title\x07hi"), "hi");
+        assert_eq!(strip("\x1b]8;;http://example.com\x1b\\link\x1b]8;;\x1b\\"), "link");
+        assert_eq!(strip("\u{9d}0;title\u{9c}hi"), "hi");
+    }
+
+    #[test]
+    fn strips_control_characters() {
+        assert_eq!(strip("nul\0byte"), "nulbyte");
+        assert_eq!(strip("bell\x07"), "bell");
+        assert_eq!(strip("tab\there\r\nnext"), "tab here  next");
+        assert_eq!(strip("abc\u{202E}fed\u{202C}"), "abcfed");
+        assert_eq!(strip("\u{2067}rtl\u{2069} \u{200F}mark"), "rtl mark");
+    }
+
+    #[test]
+    fn escapes_unsafe_characters() {
+        assert_eq!(escape("\x1b[31mred"), "\\u{1b}[31mred");
+        assert_eq!(escape("\x1b]0;title\x07"), "\\u{1b}]0;title\\u{7}");
+        assert_eq!(escape("a\u{9b}31m"), "a\\u{9b}31m");
+        assert_eq!(escape("nul\0"), "nul\\u{0}");
+        assert_eq!(escape("abc\u{202E}fed"), "abc\\u{202e}fed");
+        assert_eq!(escape("two\nlines"), "two\\nlines");
+    }
+
+    #[test]
+    fn rejects_unsafe_text() {
+        for text in ["\x1b[31mred", "\x1b]0;title\x07", "a\u{9b}31m", "nul\0", "abc\u{202E}fed", "two\nlines"] {
+            assert!(sanitize_text(text, SanitizePolicy::Reject).is_none(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn sanitizes_only_relayed_frames() {
+        let say = Frame::Say { channel: "#general".to_string(), text: "\x1b[1mhi".to_string() };
+        assert!(matches!(sanitize_frame(say, SanitizePolicy::Strip), Some(Frame::Say { text, .. }) if text == "hi"));
+        let message = Frame::PrivateMessage { to: "bob".to_string(), text: "\0".to_string() };
+        assert!(sanitize_frame(message, SanitizePolicy::Reject).is_none());
+        let join = Frame::Join { channel: "#general".to_string() };
+        assert!(matches!(sanitize_frame(join, SanitizePolicy::Reject), Some(Frame::Join { .. })));
+    }
+}
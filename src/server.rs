@@ -1,131 +1,678 @@
-use tokio::net::TcpListener;
-use tokio::sync::broadcast;
+use tokio::net::{TcpListener, UnixListener};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use tokio::io::{BufReader, AsyncBufReadExt};
-use std::fs::OpenOptions;
-use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
-use tokio::sync::Mutex;
-use termtalk::handle_client;
-use logging::log_message;
-use utils::write_to_stream;
+use tokio::sync::{Mutex, mpsc};
+use std::time::Instant;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{Duration, sleep, timeout, timeout_at};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use termtalk::{ServerState, ShutdownRequest, broadcast_notice, handle_client};
+use termtalk::admin::{AdminRequest, AdminResponse, handle_request, run_command};
+use termtalk::bans::BanList;
+use termtalk::accounts::{AccountStore, verify_password};
+use termtalk::channels::ChannelRegistry;
+use termtalk::config::{LogLevel, ServerConfig, with_port};
+use clap::Parser;
+use termtalk::mailbox::OfflineMailbox;
+use termtalk::sessions::{ResumeError, SessionInfo, SessionRegistry, generate_secret};
+use termtalk::store::{MessageStore, RetentionPolicy};
+use termtalk::transcripts::TranscriptWriter;
+use termtalk::users::{UserRegistry, UsernameError, normalize_username, validate_username};
+use termtalk::tls::{server_acceptor, split_plain, split_tls};
+use termtalk::protocol::{CAP_OFFLINE_MESSAGES, Frame, MAX_FRAME_LEN, PROTOCOL_VERSION, negotiate_capabilities, now_timestamp, read_frame, software_version};
+use termtalk::logging;
+use termtalk::outbound::Outbound;
+use termtalk::metrics::{Metrics, PROMETHEUS_CONTENT_TYPE, count_traffic, increment};
+use tracing::{Instrument, Span, debug, error, field, info, warn};
 
-mod logging;
-mod utils;
+/// TermTalk chat server
+#[derive(Parser, Clone)]
+#[command(version, about)]
+struct Cli {
+    /// Configuration file [default: config.toml]
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<String>,
+    /// Address to listen on, overrides bind_address from the configuration
+    #[arg(short, long, value_name = "HOST:PORT")]
+    bind: Option<String>,
+    /// Port to listen on, keeping the host of the bind address
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Log level: error, warn, info or debug
+    #[arg(short, long, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+}
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // Create a log file for the server
-    let log_file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open("server.log")?;
-    let log_file = Arc::new(Mutex::new(log_file));
+    // Command line flags take precedence over the environment and the config file
+    let cli = Cli::parse();
+    let config = match load_config(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Everything the server logs goes to the log file, rotated as configured
+    logging::init(&config.log_settings())?;
+
+    let accounts = AccountStore::load(&config.accounts_file)?;
+    info!(accounts_file = %config.accounts_file, allow_guests = config.allow_guests, "loaded accounts");
+    let bans = BanList::load(&config.bans_file)?;
+
+    // Keep every chat message in SQLite unless the store is disabled
+    let store = if config.store_path.is_empty() {
+        None
+    } else {
+        let retention = RetentionPolicy { max_age_days: config.retention_days, max_rows: config.retention_max_rows };
+        let store = MessageStore::open(&config.store_path, retention)?;
+        info!(path = %config.store_path, retention_days = config.retention_days, retention_max_rows = config.retention_max_rows, "storing messages");
+        Some(store)
+    };
 
-    log_message(&log_file, "Server running on port 8080").await;
+    // Readable transcripts of every channel, separate from the log
+    let transcripts = if config.transcript_dir.is_empty() {
+        None
+    } else {
+        let transcripts = TranscriptWriter::open(&config.transcript_dir, config.transcript_format)?;
+        info!(directory = %config.transcript_dir, format = ?config.transcript_format, "writing transcripts");
+        Some(transcripts)
+    };
 
-    let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    let (sender, _) = broadcast::channel(32);
+    // Encrypt every connection once a certificate and key are configured
+    let acceptor = if config.tls_cert.is_empty() {
+        warn!("TLS is disabled, connections are not encrypted");
+        None
+    } else {
+        let (acceptor, fingerprint) = server_acceptor(&config.tls_cert, &config.tls_key)?;
+        info!(certificate = %config.tls_cert, %fingerprint, "TLS enabled");
+        println!("TLS certificate fingerprint (SHA-256): {}", fingerprint);
+        Some(acceptor)
+    };
+
+    let listener = TcpListener::bind(&config.bind_address).await?;
+    info!(address = %config.bind_address, "server running");
     let client_counter = AtomicUsize::new(0);
-    let token_username_map: Arc<Mutex<HashMap<usize, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    let (shutdown_requests, mut shutdown_requested) = mpsc::unbounded_channel();
+    let state = ServerState {
+        users: Arc::new(Mutex::new(UserRegistry::default())),
+        channels: Arc::new(Mutex::new(ChannelRegistry::new(config.broadcast_capacity, config.history_size, transcripts.clone()))),
+        client_writers: Arc::new(Mutex::new(HashMap::new())),
+        accounts: Arc::new(Mutex::new(accounts)),
+        sessions: Arc::new(Mutex::new(SessionRegistry::new())),
+        mailbox: Arc::new(Mutex::new(OfflineMailbox::new())),
+        bans: Arc::new(Mutex::new(bans)),
+        config: Arc::new(RwLock::new(Arc::new(config.clone()))),
+        // The admin reload command reads the same sources again, the command line flags included
+        reload_config: Arc::new(move || {
+            let config = load_config(&cli)?;
+            logging::reconfigure(&config.log_settings()).map_err(|e| format!("cannot open {}: {}", config.log_file, e))?;
+            Ok(config)
+        }),
+        store,
+        transcripts,
+        metrics: Arc::new(Metrics::default()),
+        shutdown: CancellationToken::new(),
+        shutdown_requests,
+        started_at: Instant::now(),
+    };
+    spawn_console(state.clone());
+    if !config.admin_socket.is_empty() {
+        let listener = bind_admin_socket(&config.admin_socket)?;
+        info!(path = %config.admin_socket, "admin socket listening");
+        tokio::spawn(serve_admin_socket(listener, state.clone()));
+    }
+    if !config.metrics_address.is_empty() {
+        let listener = TcpListener::bind(&config.metrics_address).await?;
+        info!(address = %config.metrics_address, "serving metrics");
+        tokio::spawn(serve_metrics(listener, state.clone()));
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
+    // Every connection task is tracked so the shutdown can wait for them
+    let tracker = TaskTracker::new();
+    let shutdown_signal = shutdown_signal();
+    tokio::pin!(shutdown_signal);
+    let default_request = || {
+        let config = state.config();
+        ShutdownRequest { reason: config.shutdown_message.clone(), countdown_secs: config.shutdown_countdown_secs }
+    };
+
+    let (cause, request) = loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => break (format!("accepting connections failed: {}", e), default_request()),
+            },
+            name = &mut shutdown_signal => break (format!("received {}", name), default_request()),
+            Some(request) = shutdown_requested.recv() => break ("requested by an administrator".to_string(), request),
+        };
         let client_token = client_counter.fetch_add(1, Ordering::SeqCst);
-        log_message(&log_file, &format!("DEBUG: New client {} connected", client_token)).await;
+        increment(&state.metrics.connections_accepted);
+        // Every event of the connection carries its token, address and (once known) username
+        let span = tracing::info_span!("client", token = client_token, %peer, username = field::Empty);
+        span.in_scope(|| debug!("new connection"));
 
-        let sender = sender.clone();
-        let receiver = sender.subscribe();
-        let log_file_clone = Arc::clone(&log_file);
-        let token_username_map_clone = Arc::clone(&token_username_map);
+        let state = state.clone();
+        let acceptor = acceptor.clone();
+        // Read per connection so a configuration reload applies to the next ones
+        let max_clients = state.config().max_clients;
 
-        tokio::spawn(async move {
-            let (read_stream, write_stream) = stream.into_split();
+        tracker.spawn(async move {
+            let (read_stream, write_stream) = match acceptor {
+                Some(acceptor) => match timeout(Duration::from_secs(10), acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => split_tls(tls_stream),
+                    Ok(Err(e)) => {
+                        debug!(error = %e, "TLS handshake failed");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake timed out");
+                        return;
+                    }
+                },
+                None => split_plain(stream),
+            };
+            let (read_stream, write_stream) = count_traffic(&state.metrics, read_stream, write_stream);
             let mut reader = BufReader::new(read_stream);
 
-            // Wrap write_stream in an Arc<Mutex> once at the beginning
-            let write_stream = Arc::new(Mutex::new(write_stream));
+            // Everything sent to the client goes through its queue and writer task from here on
+            let write_stream = Outbound::spawn(write_stream, state.config().outbound_settings(), Arc::clone(&state.metrics));
 
-            // Send the client token to the client
-            if write_to_stream(&write_stream, &format!("Your token: {}\n", client_token)).await.is_err() {
-                log_message(&log_file_clone, &format!("DEBUG: Failed to send token to client {}", client_token)).await;
+            // Turn the connection away if the server is full
+            let slot = ActiveConnection::new(Arc::clone(&state.metrics));
+            if max_clients > 0 && slot.count > max_clients {
+                warn!(max_clients, "rejecting connection, the server is full");
+                increment(&state.metrics.connections_rejected);
+                let error = Frame::Error { message: "The server is full, please try again later.".to_string() };
+                let _ = write_stream.send(&error);
                 return;
             }
 
-            let mut username = String::new();
-            let username_clone;
+            // Wait for the client to state its protocol version and capabilities
+            let hello = tokio::select! {
+                read = timeout(Duration::from_secs(10), read_frame(&mut reader, MAX_FRAME_LEN)) => read,
+                _ = state.shutdown.cancelled() => return,
+            };
+            let hello = match hello {
+                Ok(Ok(Some(frame))) => {
+                    state.metrics.record_command(frame.kind());
+                    frame
+                }
+                Ok(Ok(None)) => {
+                    debug!("disconnected before hello");
+                    return;
+                }
+                Ok(Err(_)) | Err(_) => {
+                    // Most likely a client that predates the JSON protocol
+                    debug!("no valid hello");
+                    let error = Frame::Error {
+                        message: format!("Expected a hello frame. This server speaks protocol version {}, please upgrade your client.", PROTOCOL_VERSION),
+                    };
+                    let _ = write_stream.send(&error);
+                    return;
+                }
+            };
 
-            loop {
-                username.clear();
-                if write_to_stream(&write_stream, "Enter your username: ").await.is_err() {
-                    log_message(&log_file_clone, &format!("DEBUG: Failed to prompt client {} for username", client_token)).await;
+            let capabilities = match hello {
+                Frame::Hello { version, software, capabilities } if version == PROTOCOL_VERSION => {
+                    debug!(%software, ?capabilities, "hello");
+                    negotiate_capabilities(&capabilities)
+                }
+                Frame::Hello { version, software, .. } => {
+                    info!(%software, version, "rejecting unsupported protocol version");
+                    let error = Frame::Error {
+                        message: format!(
+                            "Unsupported protocol version {} (client {}). This server ({}) speaks protocol version {}.",
+                            version, software, software_version(), PROTOCOL_VERSION
+                        ),
+                    };
+                    let _ = write_stream.send(&error);
                     return;
                 }
-                if reader.read_line(&mut username).await.is_err() {
-                    log_message(&log_file_clone, &format!("DEBUG: Failed to read username from client {}", client_token)).await;
+                other => {
+                    debug!(frame = ?other, "expected hello");
+                    let error = Frame::Error { message: "Expected a hello frame.".to_string() };
+                    let _ = write_stream.send(&error);
                     return;
                 }
-                let username = username.trim().to_string();
+            };
 
-                // Check if the username is already in use by another client
-                {
-                    let map = token_username_map_clone.lock().await;
-                    if map.values().any(|existing_username| existing_username == &username) {
-                        let error_message = "ERROR: Username is already taken. Please choose a different one.\n";
-                        log_message(&log_file_clone, &format!("DEBUG: Username '{}' is already taken", username)).await;
-                        if write_to_stream(&write_stream, &error_message).await.is_err() {
-                            log_message(&log_file_clone, &format!("DEBUG: Failed to send error message to client {}", client_token)).await;
+            // Accept the version and send the session secret with the negotiated capabilities.
+            // The numeric client token stays internal, the secret is what a client needs to resume.
+            let secret = generate_secret();
+            let welcome = Frame::Welcome {
+                version: PROTOCOL_VERSION,
+                software: software_version(),
+                capabilities: capabilities.clone(),
+                token: secret.clone(),
+            };
+            if write_stream.send(&welcome).is_err() {
+                debug!("failed to send the welcome");
+                return;
+            }
+
+            // However many attempts it takes, the login has to be done in time
+            let login_deadline = tokio::time::Instant::now() + Duration::from_secs(state.config().login_timeout_secs);
+            let (session, resumed) = loop {
+                let read = tokio::select! {
+                    read = timeout_at(login_deadline, read_frame(&mut reader, MAX_FRAME_LEN)) => read,
+                    _ = state.shutdown.cancelled() => return,
+                };
+                let Ok(read) = read else {
+                    debug!("no login in time");
+                    let _ = write_stream.send(&Frame::Error { message: "Login timed out.".to_string() });
+                    return;
+                };
+                if let Ok(Some(frame)) = &read {
+                    state.metrics.record_command(frame.kind());
+                }
+                let (username, password) = match read {
+                    Ok(Some(Frame::Resume { token })) => {
+                        // Hand the old session over to this connection, it keeps its name and channels
+                        let result = state.sessions.lock().await.resume(&token, &secret);
+                        let message = match result {
+                            Ok(mut resumed) => {
+                                // The new connection may have negotiated different capabilities
+                                resumed.info.capabilities = capabilities.clone();
+                                let info = resumed.info.clone();
+                                Span::current().record("username", info.username.as_str());
+                                info!(old_token = info.client_token, "resumed session");
+                                // A connection that is already gone is noticed by handle_client, which detaches the session again
+                                let _ = write_stream.send(&Frame::LoginAccepted { username: info.username.clone(), registered: info.registered, resumed: true });
+                                break (info, Some(resumed));
+                            }
+                            Err(ResumeError::StillConnected) => "The session is still active on another connection, try again in a moment.",
+                            Err(ResumeError::UnknownSession) => "Unknown or expired session.",
+                        };
+                        debug!(reason = message, "failed to resume");
+                        if write_stream.send(&Frame::Error { message: message.to_string() }).is_err() {
+                            return;
                         }
-                        continue; // Prompt the client to enter a new username
+                        continue;
+                    }
+                    Ok(Some(Frame::Login { username, password })) => (normalize_username(&username), password),
+                    Ok(Some(other)) => {
+                        debug!(frame = ?other, "expected login");
+                        let error = Frame::Error { message: "Expected a login frame.".to_string() };
+                        if write_stream.send(&error).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    Ok(None) => {
+                        debug!("disconnected during login");
+                        return;
+                    }
+                    Err(e) => {
+                        debug!(error = %e, "failed to read the login");
+                        return;
                     }
+                };
+
+                // Refuse names that can never be used before looking at bans and passwords
+                if let Err(e) = validate_username(&username) {
+                    debug!(%username, reason = %e, "invalid username");
+                    if write_stream.send(&Frame::Error { message: e.to_string() }).is_err() {
+                        return;
+                    }
+                    continue;
                 }
 
-                // Add the token-username mapping to the HashMap
-                {
-                    let mut map = token_username_map_clone.lock().await;
-                    map.insert(client_token, username.clone());
-                    log_message(&log_file_clone, &format!("DEBUG: Added token-username mapping: {} -> {}", client_token, username)).await;
+                let ban = state.bans.lock().await.get(&username).cloned();
+                if let Some(ban) = ban {
+                    info!(%username, "refusing banned user");
+                    let message = if ban.reason.is_empty() {
+                        "You are banned from this server.".to_string()
+                    } else {
+                        format!("You are banned from this server: {}", ban.reason)
+                    };
+                    if write_stream.send(&Frame::Error { message }).is_err() {
+                        return;
+                    }
+                    continue;
                 }
 
-                // Send success message to the client
-                let success_message = "SUCCESS: Username accepted.\n";
-                if write_to_stream(&write_stream, &success_message).await.is_err() {
-                    log_message(&log_file_clone, &format!("DEBUG: Failed to send success message to client {}", client_token)).await;
-                    return;
+                // Registered usernames need their password, everyone else logs in as a guest
+                let password_hash = state.accounts.lock().await.password_hash(&username);
+                let registered = password_hash.is_some();
+                if let Some(password_hash) = password_hash {
+                    let password = password.unwrap_or_default();
+                    let valid = tokio::task::spawn_blocking(move || verify_password(&password, &password_hash)).await.unwrap_or(false);
+                    if !valid {
+                        warn!(%username, "wrong password");
+                        // Slow down password guessing
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        let error = Frame::Error { message: format!("'{}' is a registered username, the password is missing or wrong.", username) };
+                        if write_stream.send(&error).is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                } else if !state.config().allow_guests {
+                    let error = Frame::Error { message: "Guest logins are disabled on this server, please log in with a registered username.".to_string() };
+                    if write_stream.send(&error).is_err() {
+                        return;
+                    }
+                    continue;
+                } else if let Some(registered) = state.accounts.lock().await.lookalike(&username) {
+                    // A guest must not pass for a registered user, even while that user is offline
+                    warn!(%username, %registered, "refusing a lookalike of a registered username");
+                    if write_stream.send(&Frame::Error { message: UsernameError::Lookalike.to_string() }).is_err() {
+                        return;
+                    }
+                    continue;
                 }
 
-                // Broadcast the welcome message
-                let welcome_message = format!("SERVER: {} has joined the chat!\n", username);
-                let _ = sender.send((username.clone(), welcome_message.clone()));
-                username_clone = username.clone();
-                break;
-            }
+                // Claim the username, unless another client already has it
+                {
+                    let mut users = state.users.lock().await;
+                    if let Err(e) = users.reserve(client_token, &username) {
+                        debug!(%username, reason = %e, "username refused");
+                        if e == UsernameError::Taken {
+                            increment(&state.metrics.username_collisions);
+                        }
+                        if write_stream.send(&Frame::Error { message: e.to_string() }).is_err() {
+                            debug!("failed to send the error");
+                        }
+                        continue; // Prompt the client to enter a new username
+                    }
+                    state.mailbox.lock().await.remember(&username);
+                    Span::current().record("username", username.as_str());
+                    info!(registered, "logged in");
+                }
+
+                // Send success message to the client, the join notice goes out with the default channel.
+                // From here on the session is cleaned up by handle_client alone, even if the connection is already gone.
+                let _ = write_stream.send(&Frame::LoginAccepted { username: username.clone(), registered, resumed: false });
 
-            // Clone username_clone before passing it to handle_client
-            let username_clone_for_log = username_clone.clone();
+                state.sessions.lock().await.register(&secret, client_token);
 
-            // Clone token_username_map_clone before passing it to handle_client
-            let token_username_map_for_handle = Arc::clone(&token_username_map_clone);
+                // Hand over what was queued while the user was offline
+                let offline = state.mailbox.lock().await.take(&username);
+                if !offline.is_empty() {
+                    debug!(count = offline.len(), "delivering offline messages");
+                    let delivered = if capabilities.iter().any(|cap| cap == CAP_OFFLINE_MESSAGES) {
+                        write_stream.send(&Frame::OfflineMessages { messages: offline.clone() })
+                    } else {
+                        deliver_offline_as_notices(&write_stream, &offline)
+                    };
+                    if delivered.is_err() {
+                        // Keep them for the next login, handle_client notices the dead connection
+                        let mut mailbox = state.mailbox.lock().await;
+                        for frame in offline {
+                            mailbox.push(&username, frame);
+                        }
+                    }
+                }
+                break (SessionInfo { client_token, username, registered, capabilities: capabilities.clone() }, None);
+            };
 
-            // Handle the client with their chosen username and token
+            // Handle the client with their chosen username and token.
+            // It removes the token-username mapping itself once the session is over.
             handle_client(
                 reader,
                 write_stream,
-                sender,
-                receiver,
-                username_clone,
-                client_token,
-                token_username_map_for_handle, // Use the cloned Arc here
+                session,
+                resumed,
+                state,
             ).await;
 
-            // Remove the token-username mapping when the client disconnects
-            {
-                let mut map = token_username_map_clone.lock().await;
-                map.remove(&client_token);
-                log_message(&log_file_clone, &format!("DEBUG: Removed token-username mapping: {} -> {}", client_token, username_clone_for_log)).await;
+            debug!("connection closed");
+        }.instrument(span));
+    };
+
+    // Stop accepting before telling anyone, nobody new should join a server that is going away
+    drop(listener);
+    drop(shutdown_requested);
+    info!(%cause, "shutting down");
+    graceful_shutdown(&state, request, &tracker).await;
+    if !config.admin_socket.is_empty() {
+        let _ = std::fs::remove_file(&config.admin_socket);
+    }
+    Ok(())
+}
+
+// The config file and environment, with the command line flags on top
+fn load_config(cli: &Cli) -> Result<ServerConfig, String> {
+    let mut config = ServerConfig::load(cli.config.as_deref())?;
+    if let Some(bind) = &cli.bind {
+        config.bind_address = bind.clone();
+    }
+    if let Some(port) = cli.port {
+        config.bind_address = with_port(&config.bind_address, port);
+    }
+    if let Some(level) = cli.log_level {
+        config.log_level = level;
+    }
+    Ok(config)
+}
+
+// Bind the admin socket, replacing a file left behind by a server that did not stop cleanly.
+// Only the server user may connect, the socket grants full control over the server.
+fn bind_admin_socket(path: &str) -> std::io::Result<UnixListener> {
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(Error::new(ErrorKind::AddrInUse, format!("{} is in use, is another server running?", path)));
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // The socket is created with the permissions of the umask. Create it in a directory only the
+    // server user can enter and move it into place once it is restricted, so nobody can connect
+    // in between.
+    let private_dir = format!("{}.{}.tmp", path, std::process::id());
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let bound = (|| {
+        let private_path = std::path::Path::new(&private_dir).join("socket");
+        let listener = UnixListener::bind(&private_path)?;
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    })();
+    let _ = std::fs::remove_dir_all(&private_dir);
+    bound
+}
+
+// Requests from termtalk-admin, one JSON request per line, each answered with one JSON line
+async fn serve_admin_socket(listener: UnixListener, state: ServerState) {
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!(error = %e, "admin socket failed");
+                    return;
+                }
+            },
+            _ = state.shutdown.cancelled() => return,
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let response = match serde_json::from_str::<AdminRequest>(&line) {
+                    Ok(request) => {
+                        info!(?request, "admin request");
+                        handle_request(&state, request).await
+                    }
+                    Err(e) => AdminResponse::Error { message: format!("Invalid request: {}", e) },
+                };
+                let Ok(mut json) = serde_json::to_string(&response) else { break };
+                json.push('\n');
+                if writer.write_all(json.as_bytes()).await.is_err() {
+                    break;
+                }
             }
+        });
+    }
+}
 
-            log_message(&log_file_clone, &format!("DEBUG: Client {} disconnected", client_token)).await;
+// Prometheus scrapes and health checks. A bare HTTP/1.1 responder is enough for that,
+// it answers one GET per connection and closes it.
+async fn serve_metrics(listener: TcpListener, state: ServerState) {
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!(error = %e, "metrics listener failed");
+                    return;
+                }
+            },
+            _ = state.shutdown.cancelled() => return,
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            // Nothing a scraper sends comes close to this, anything longer is not worth reading
+            let mut reader = BufReader::new(reader.take(8192));
+            let Ok(Ok(request_line)) = timeout(Duration::from_secs(5), read_http_request(&mut reader)).await else {
+                return;
+            };
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default();
+            let (status, content_type, body) = match (method, path) {
+                ("GET", "/metrics") => {
+                    let users = state.users.lock().await.len();
+                    ("200 OK", PROMETHEUS_CONTENT_TYPE, state.metrics.render(users, state.started_at.elapsed()))
+                }
+                ("GET", "/healthz") => ("200 OK", "text/plain; charset=utf-8", "ok\n".to_string()),
+                ("GET", _) => ("404 Not Found", "text/plain; charset=utf-8", "not found\n".to_string()),
+                _ => ("405 Method Not Allowed", "text/plain; charset=utf-8", "method not allowed\n".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status, content_type, body.len(), body
+            );
+            let _ = writer.write_all(response.as_bytes()).await;
+            let _ = writer.shutdown().await;
         });
     }
+}
+
+// The request line of an HTTP request, after reading up to the blank line that ends the headers
+async fn read_http_request(reader: &mut (impl AsyncBufReadExt + Unpin)) -> std::io::Result<String> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            return Ok(request_line);
+        }
+    }
+}
+
+// Admin commands typed at the server terminal. Stdin is read on a plain thread,
+// a pending blocking read would otherwise hold up the runtime on shutdown.
+fn spawn_console(state: ServerState) {
+    let (lines_sender, mut lines) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if lines_sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        println!("Admin console ready, type /help for the list of commands.");
+        while let Some(line) = lines.recv().await {
+            let output = run_command(&state, &line).await;
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    });
+}
+
+// Resolves with the name of the first SIGINT (Ctrl+C) or SIGTERM
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+// Count down while warning the clients, close every connection, then wait for the connection
+// tasks (up to the configured deadline) and make sure everything is on disk.
+// A second Ctrl+C skips the rest of the countdown.
+async fn graceful_shutdown(state: &ServerState, request: ShutdownRequest, tracker: &TaskTracker) {
+    let config = state.config();
+    let reason = request.reason.as_str();
+    let mut remaining = request.countdown_secs;
+    while remaining > 0 {
+        if remaining == request.countdown_secs || remaining.is_multiple_of(10) || remaining <= 5 {
+            let unit = if remaining == 1 { "second" } else { "seconds" };
+            broadcast_notice(state, &format!("Disconnecting in {} {}: {}", remaining, unit, reason)).await;
+        }
+        tokio::select! {
+            _ = sleep(Duration::from_secs(1)) => remaining -= 1,
+            _ = tokio::signal::ctrl_c() => {
+                info!("countdown interrupted, shutting down now");
+                break;
+            }
+        }
+    }
+    broadcast_notice(state, reason).await;
+
+    // Every handle_client loop ends on the cancellation and closes its connection
+    state.shutdown.cancel();
+    tracker.close();
+    let deadline = Duration::from_secs(config.shutdown_timeout_secs);
+    if timeout(deadline, tracker.wait()).await.is_err() {
+        warn!(open = tracker.len(), timeout_secs = deadline.as_secs(), "connections did not close in time");
+    }
+
+    if let Some(store) = state.store.clone() {
+        let _ = tokio::task::spawn_blocking(move || store.flush()).await;
+    }
+    if let Some(transcripts) = state.transcripts.clone() {
+        let _ = tokio::task::spawn_blocking(move || transcripts.flush()).await;
+    }
+    info!("server stopped");
+    let _ = tokio::task::spawn_blocking(logging::flush).await;
+}
+
+// Counts a connection as connected until it is dropped at the end of the connection task
+struct ActiveConnection {
+    metrics: Arc<Metrics>,
+    count: usize, // connected clients including this one
+}
+
+impl ActiveConnection {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        let count = metrics.connected_clients.fetch_add(1, Ordering::SeqCst) + 1;
+        ActiveConnection { metrics, count }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.metrics.connected_clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Clients without the offline_messages capability get the queued frames one by one after a notice
+fn deliver_offline_as_notices(write_stream: &Outbound, offline: &[Frame]) -> std::io::Result<()> {
+    let notice = Frame::System { channel: None, text: "While you were away:".to_string(), timestamp: now_timestamp() };
+    write_stream.send(&notice)?;
+    for frame in offline {
+        write_stream.send(frame)?;
+    }
     Ok(())
 }
@@ -0,0 +1,185 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use crate::channels::Event;
+use crate::protocol::Frame;
+use crate::supervisor::{DisconnectReason, Supervisor};
+
+// Frames kept for a disconnected session, older ones are dropped first
+const MAX_MISSED_FRAMES: usize = 500;
+
+// Random session secret handed to the client in the welcome frame
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("no system random number generator available");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Who is behind a connection once the login is done
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub client_token: usize,
+    pub username: String,
+    pub registered: bool,
+    pub capabilities: Vec<String>, // negotiated during the handshake
+}
+
+// A session whose connection dropped, waiting for the client to come back
+pub struct DetachedSession {
+    pub info: SessionInfo,
+    pub channels: Vec<String>,
+    detached_at: Instant,
+    missed: Arc<Mutex<VecDeque<Frame>>>,
+    collectors: Vec<JoinHandle<()>>,
+}
+
+impl DetachedSession {
+    // Start collecting everything the session's channels broadcast while it is away
+    pub fn new(info: SessionInfo, receivers: Vec<(String, broadcast::Receiver<Arc<Event>>)>) -> Self {
+        let missed = Arc::new(Mutex::new(VecDeque::new()));
+        let mut channels = Vec::new();
+        let mut collectors = Vec::new();
+        for (channel, mut receiver) in receivers {
+            let missed = Arc::clone(&missed);
+            channels.push(channel);
+            collectors.push(tokio::spawn(async move {
+                loop {
+                    match receiver.recv().await {
+                        // User lists are stale by the time the client is back
+                        Ok(event) if matches!(event.frame, Frame::UserList { .. }) => {}
+                        Ok(event) => push_bounded(&missed, event.frame.clone()),
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            }));
+        }
+        DetachedSession { info, channels, detached_at: Instant::now(), missed, collectors }
+    }
+
+    // Queue a frame addressed to this user directly, such as a private message
+    pub fn push(&self, frame: Frame) {
+        push_bounded(&self.missed, frame);
+    }
+
+    // Stop collecting and hand over what was missed, oldest first
+    pub fn finish(self) -> Vec<Frame> {
+        for collector in &self.collectors {
+            collector.abort();
+        }
+        let mut missed = self.missed.lock().unwrap_or_else(|e| e.into_inner());
+        missed.drain(..).collect()
+    }
+}
+
+fn push_bounded(missed: &Mutex<VecDeque<Frame>>, frame: Frame) {
+    let mut missed = missed.lock().unwrap_or_else(|e| e.into_inner());
+    if missed.len() >= MAX_MISSED_FRAMES {
+        missed.pop_front();
+    }
+    missed.push_back(frame);
+}
+
+// Why a resume attempt was refused
+#[derive(Debug, PartialEq)]
+pub enum ResumeError {
+    UnknownSession,
+    StillConnected,
+}
+
+// Session secrets of live and detached connections
+#[derive(Default)]
+pub struct SessionRegistry {
+    secrets: HashMap<String, usize>, // secret -> client token
+    live: HashMap<usize, Supervisor>, // client token -> supervisor of the connection
+    detached: HashMap<usize, DetachedSession>,
+}
+
+impl SessionRegistry {
+    pub fn new() -> Self {
+        SessionRegistry::default()
+    }
+
+    // Remember the secret of a freshly logged in connection
+    pub fn register(&mut self, secret: &str, client_token: usize) {
+        self.secrets.insert(secret.to_string(), client_token);
+    }
+
+    // Mark the connection as live, its supervisor is stopped when it has to be dropped
+    pub fn attach(&mut self, client_token: usize, supervisor: Supervisor) {
+        self.live.insert(client_token, supervisor);
+    }
+
+    // Tell a live connection to end its session, returns false if it is not live
+    pub fn kick(&self, client_token: usize, reason: &str) -> bool {
+        match self.live.get(&client_token) {
+            Some(supervisor) => {
+                supervisor.stop(DisconnectReason::Kicked(reason.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_detached(&self, client_token: usize) -> bool {
+        self.detached.contains_key(&client_token)
+    }
+
+    // Take a detached session out before its grace period ends, for example when its user is kicked
+    pub fn take_detached(&mut self, client_token: usize) -> Option<DetachedSession> {
+        self.detached.remove(&client_token)
+    }
+
+    pub fn detached_count(&self) -> usize {
+        self.detached.len()
+    }
+
+    // Keep a dropped session around until it is resumed or expires
+    pub fn detach(&mut self, session: DetachedSession) {
+        self.live.remove(&session.info.client_token);
+        self.detached.insert(session.info.client_token, session);
+    }
+
+    // Take the session out once it has been detached for the whole grace period.
+    // A session that was resumed and dropped again in the meantime is not expired yet.
+    pub fn take_expired(&mut self, client_token: usize, grace: Duration) -> Option<DetachedSession> {
+        match self.detached.get(&client_token) {
+            Some(session) if session.detached_at.elapsed() >= grace => self.detached.remove(&client_token),
+            _ => None,
+        }
+    }
+
+    // Forget the session entirely, its secret can no longer be resumed
+    pub fn remove(&mut self, client_token: usize) {
+        self.live.remove(&client_token);
+        self.detached.remove(&client_token);
+        self.secrets.retain(|_, token| *token != client_token);
+    }
+
+    // Queue a frame for a detached session, returns false if the client is not detached
+    pub fn push_missed(&self, client_token: usize, frame: Frame) -> bool {
+        match self.detached.get(&client_token) {
+            Some(session) => {
+                session.push(frame);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Hand a detached session to a new connection, which from now on answers to new_secret.
+    // If the old connection still looks alive it is told to drop so a retry can succeed.
+    pub fn resume(&mut self, secret: &str, new_secret: &str) -> Result<DetachedSession, ResumeError> {
+        let client_token = *self.secrets.get(secret).ok_or(ResumeError::UnknownSession)?;
+        if let Some(supervisor) = self.live.get(&client_token) {
+            supervisor.stop(DisconnectReason::Takeover);
+            return Err(ResumeError::StillConnected);
+        }
+        let session = self.detached.remove(&client_token).ok_or(ResumeError::UnknownSession)?;
+        self.secrets.remove(secret);
+        self.secrets.insert(new_secret.to_string(), client_token);
+        Ok(session)
+    }
+}
@@ -0,0 +1,127 @@
+use rusqlite::{Connection, params};
+use std::io::Error;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+use crate::protocol::{Frame, now_timestamp};
+
+// How often old messages are pruned while the server runs
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How long and how many messages to keep, 0 means no limit
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub max_age_days: u64,
+    pub max_rows: u64,
+}
+
+// Work for the thread that owns the database
+enum StoreCommand {
+    Record(Frame),
+    Flush(mpsc::Sender<()>), // answered once everything queued before it is written
+}
+
+// Handle to the SQLite message store. The database is owned by a background thread,
+// so recording a message never blocks the async connection tasks.
+#[derive(Clone)]
+pub struct MessageStore {
+    sender: mpsc::Sender<StoreCommand>,
+}
+
+impl MessageStore {
+    // Open (or create) the database and start the thread that writes to it and prunes it
+    pub fn open(path: &str, retention: RetentionPolicy) -> std::io::Result<Self> {
+        let conn = Connection::open(path).map_err(to_io_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 id        INTEGER PRIMARY KEY,
+                 timestamp INTEGER NOT NULL,
+                 kind      TEXT NOT NULL,
+                 sender    TEXT NOT NULL,
+                 target    TEXT NOT NULL,
+                 text      TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+             CREATE INDEX IF NOT EXISTS messages_target ON messages (target, timestamp);",
+        )
+        .map_err(to_io_error)?;
+        prune(&conn, retention).map_err(to_io_error)?;
+
+        let (sender, receiver) = mpsc::channel::<StoreCommand>();
+        thread::Builder::new().name("message-store".to_string()).spawn(move || {
+            let mut last_prune = Instant::now();
+            loop {
+                match receiver.recv_timeout(PRUNE_INTERVAL) {
+                    Ok(StoreCommand::Record(frame)) => {
+                        if let Err(e) = insert(&conn, &frame) {
+                            error!(error = %e, "failed to store message");
+                        }
+                    }
+                    Ok(StoreCommand::Flush(done)) => {
+                        let _ = done.send(());
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    // Every handle is gone, the server is shutting down
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if last_prune.elapsed() >= PRUNE_INTERVAL {
+                    if let Err(e) = prune(&conn, retention) {
+                        error!(error = %e, "failed to prune the message store");
+                    }
+                    last_prune = Instant::now();
+                }
+            }
+        })?;
+
+        Ok(MessageStore { sender })
+    }
+
+    // Queue a chat line or a private message for storage, other frames are ignored
+    pub fn record(&self, frame: &Frame) {
+        if matches!(frame, Frame::Chat { .. } | Frame::Direct { .. }) {
+            let _ = self.sender.send(StoreCommand::Record(frame.clone()));
+        }
+    }
+
+    // Block until every message recorded so far is in the database
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(StoreCommand::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+fn insert(conn: &Connection, frame: &Frame) -> rusqlite::Result<()> {
+    // Private messages are stored with the recipient as "@user" in the target column
+    let (kind, sender, target, text, timestamp) = match frame {
+        Frame::Chat { channel, from, text, timestamp } => ("chat", from, channel.clone(), text, timestamp),
+        Frame::Direct { from, to, text, timestamp } => ("direct", from, format!("@{}", to), text, timestamp),
+        _ => return Ok(()),
+    };
+    conn.execute(
+        "INSERT INTO messages (timestamp, kind, sender, target, text) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![timestamp, kind, sender, target, text],
+    )?;
+    Ok(())
+}
+
+// Drop messages older than the maximum age, then the oldest ones beyond the row limit
+fn prune(conn: &Connection, retention: RetentionPolicy) -> rusqlite::Result<()> {
+    if retention.max_age_days > 0 {
+        let cutoff = now_timestamp() - (retention.max_age_days * 24 * 60 * 60) as i64;
+        conn.execute("DELETE FROM messages WHERE timestamp < ?1", params![cutoff])?;
+    }
+    if retention.max_rows > 0 {
+        conn.execute(
+            "DELETE FROM messages WHERE id NOT IN (SELECT id FROM messages ORDER BY id DESC LIMIT ?1)",
+            params![retention.max_rows as i64],
+        )?;
+    }
+    Ok(())
+}
+
+fn to_io_error(e: rusqlite::Error) -> Error {
+    Error::other(e.to_string())
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::Instrument;
+
+// Why a connection ended, logged and counted once per connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    Quit, // the client said goodbye
+    ConnectionLost, // the client closed the connection or reading from it failed
+    PingTimeout,
+    WriteError(String), // writing failed or timed out, or the client could not keep up
+    Kicked(String), // removed by an administrator, with the reason shown to the user
+    Takeover, // the session is being resumed on another connection
+    Shutdown,
+}
+
+impl DisconnectReason {
+    // Short name for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DisconnectReason::Quit => "quit",
+            DisconnectReason::ConnectionLost => "connection_lost",
+            DisconnectReason::PingTimeout => "ping_timeout",
+            DisconnectReason::WriteError(_) => "write_error",
+            DisconnectReason::Kicked(_) => "kicked",
+            DisconnectReason::Takeover => "takeover",
+            DisconnectReason::Shutdown => "shutdown",
+        }
+    }
+
+    // Whether the session ends with the connection, otherwise it waits for the client to resume it
+    pub fn ends_session(&self) -> bool {
+        matches!(self, DisconnectReason::Quit | DisconnectReason::Kicked(_) | DisconnectReason::Shutdown)
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Quit => write!(f, "the client quit"),
+            DisconnectReason::ConnectionLost => write!(f, "connection lost"),
+            DisconnectReason::PingTimeout => write!(f, "no answer to the ping"),
+            DisconnectReason::WriteError(error) => write!(f, "write error: {}", error),
+            DisconnectReason::Kicked(reason) => write!(f, "kicked: {}", reason),
+            DisconnectReason::Takeover => write!(f, "session resumed on another connection"),
+            DisconnectReason::Shutdown => write!(f, "server shutdown"),
+        }
+    }
+}
+
+// Owns the tasks of one connection. The first reason to stop it cancels all of them at once,
+// and the server shutdown stops every connection the same way.
+#[derive(Clone)]
+pub struct Supervisor {
+    token: CancellationToken,
+    reason: Arc<Mutex<Option<DisconnectReason>>>,
+    tasks: TaskTracker,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: &CancellationToken) -> Self {
+        Supervisor { token: shutdown.child_token(), reason: Arc::new(Mutex::new(None)), tasks: TaskTracker::new() }
+    }
+
+    // Stop the connection, a reason given earlier takes precedence
+    pub fn stop(&self, reason: DisconnectReason) {
+        self.reason.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert(reason);
+        self.token.cancel();
+    }
+
+    // Resolves with the reason once the connection is stopped
+    pub async fn stopped(&self) -> DisconnectReason {
+        self.token.cancelled().await;
+        self.reason()
+    }
+
+    // Why the connection was stopped; without a reason of its own it was the server shutdown
+    pub fn reason(&self) -> DisconnectReason {
+        self.reason.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or(DisconnectReason::Shutdown)
+    }
+
+    // Run a task of the connection until it is done or the connection is stopped
+    pub fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) -> JoinHandle<()> {
+        let token = self.token.clone();
+        self.tasks.spawn(
+            async move {
+                tokio::select! {
+                    _ = task => {}
+                    _ = token.cancelled() => {}
+                }
+            }
+            .in_current_span(),
+        )
+    }
+
+    // Wait for every task to end, once the connection is stopped
+    pub async fn join(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
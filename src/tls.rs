@@ -0,0 +1,144 @@
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+// Both halves of a connection, plain TCP or TLS
+pub type BoxedReader = Box<dyn AsyncRead + Send + Unpin>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+pub fn split_plain(stream: TcpStream) -> (BoxedReader, BoxedWriter) {
+    let (read_stream, write_stream) = stream.into_split();
+    (Box::new(read_stream), Box::new(write_stream))
+}
+
+pub fn split_tls<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> (BoxedReader, BoxedWriter) {
+    let (read_stream, write_stream) = tokio::io::split(stream);
+    (Box::new(read_stream), Box::new(write_stream))
+}
+
+// SHA-256 of the DER encoded certificate as lowercase hex, the form clients pin
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Accept "AB:CD:..." as well as "abcd..." in configuration
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| c.is_ascii_hexdigit()).collect::<String>().to_lowercase()
+}
+
+fn load_certificates(path: &str) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Cannot read certificates from {}: {}", path, e)))?;
+    if certificates.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("No certificates found in {}", path)));
+    }
+    Ok(certificates)
+}
+
+// Build the server side from PEM files, returns the acceptor and the fingerprint of the certificate
+pub fn server_acceptor(cert_path: &str, key_path: &str) -> std::io::Result<(TlsAcceptor, String)> {
+    let certificates = load_certificates(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Cannot read the private key from {}: {}", key_path, e)))?;
+    let fingerprint = certificate_fingerprint(&certificates[0]);
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid certificate or key: {}", e)))?;
+    Ok((TlsAcceptor::from(Arc::new(config)), fingerprint))
+}
+
+// Build the client side. A pinned fingerprint takes precedence over a CA file,
+// without either the server certificate has to chain to a public root.
+pub fn client_connector(ca_file: &str, fingerprint: &str) -> std::io::Result<TlsConnector> {
+    let config = if !fingerprint.is_empty() {
+        let verifier = PinnedCertVerifier {
+            fingerprint: normalize_fingerprint(fingerprint),
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+        };
+        rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        if ca_file.is_empty() {
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        } else {
+            for certificate in load_certificates(ca_file)? {
+                roots
+                    .add(certificate)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid CA certificate in {}: {}", ca_file, e)))?;
+            }
+        }
+        rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// The name to check the server certificate against, taken from "host:port" unless configured
+pub fn server_name(address: &str, configured: &str) -> std::io::Result<ServerName<'static>> {
+    let name = if configured.is_empty() {
+        address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address).trim_matches(|c| c == '[' || c == ']')
+    } else {
+        configured
+    };
+    ServerName::try_from(name.to_string()).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("Invalid server name '{}': {}", name, e)))
+}
+
+// Trusts exactly one certificate, identified by its SHA-256 fingerprint, which makes self-signed
+// certificates usable without a CA. Handshake signatures are still checked against that certificate.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = certificate_fingerprint(end_entity);
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("server certificate fingerprint {} does not match the pinned one", fingerprint)))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,173 @@
+use chrono::{DateTime, Local, NaiveDate};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+use tracing::error;
+use crate::config::TranscriptFormat;
+use crate::protocol::now_timestamp;
+
+// Private messages are kept in their own transcript, next to the channel ones. No channel name
+// contains '@', so a channel called #private cannot share the directory.
+const PRIVATE_TRANSCRIPT: &str = "@private";
+
+// Transcript files kept open at once, the one written least recently is closed to make room
+const MAX_OPEN_FILES: usize = 32;
+
+// Something that happened in the conversation, as it appears in a transcript
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TranscriptEvent {
+    Message { channel: String, from: String, text: String },
+    Join { channel: String, username: String },
+    Leave { channel: String, username: String },
+    Notice { channel: String, text: String },
+    Direct { from: String, to: String, text: String },
+}
+
+// One JSON line of a transcript
+#[derive(Serialize)]
+struct TranscriptLine<'a> {
+    time: String,
+    timestamp: i64,
+    #[serde(flatten)]
+    event: &'a TranscriptEvent,
+}
+
+// Work for the thread that owns the transcript files
+enum TranscriptCommand {
+    Record(i64, TranscriptEvent),
+    Flush(mpsc::Sender<()>), // answered once everything queued before it is written
+}
+
+// Handle to the transcript writer. Every channel gets a directory with one file per day,
+// written by a background thread like the message store.
+#[derive(Clone)]
+pub struct TranscriptWriter {
+    sender: mpsc::Sender<TranscriptCommand>,
+}
+
+impl TranscriptWriter {
+    pub fn open(directory: &str, format: TranscriptFormat) -> std::io::Result<Self> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory)?;
+        let (sender, receiver) = mpsc::channel::<TranscriptCommand>();
+        thread::Builder::new().name("transcripts".to_string()).spawn(move || {
+            let mut files = TranscriptFiles { directory, format, open: HashMap::new(), writes: 0 };
+            // Every handle is gone once the server shuts down
+            while let Ok(command) = receiver.recv() {
+                match command {
+                    TranscriptCommand::Record(timestamp, event) => {
+                        if let Err(e) = files.write(timestamp, &event) {
+                            error!(error = %e, "failed to write transcript");
+                        }
+                    }
+                    TranscriptCommand::Flush(done) => {
+                        for open in files.open.values() {
+                            let _ = open.file.sync_all();
+                        }
+                        let _ = done.send(());
+                    }
+                }
+            }
+        })?;
+        Ok(TranscriptWriter { sender })
+    }
+
+    pub fn record(&self, event: TranscriptEvent) {
+        self.record_at(now_timestamp(), event);
+    }
+
+    // Record with the timestamp the event already carries, such as the one of a chat frame
+    pub fn record_at(&self, timestamp: i64, event: TranscriptEvent) {
+        let _ = self.sender.send(TranscriptCommand::Record(timestamp, event));
+    }
+
+    // Block until every event recorded so far is on disk
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(TranscriptCommand::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+// A transcript file that is open for appending
+struct OpenFile {
+    date: NaiveDate, // the day the file is for
+    file: File,
+    last_write: u64, // number of the last write that went to this file
+}
+
+struct TranscriptFiles {
+    directory: PathBuf,
+    format: TranscriptFormat,
+    open: HashMap<String, OpenFile>, // transcript name -> its current file
+    writes: u64,
+}
+
+impl TranscriptFiles {
+    fn write(&mut self, timestamp: i64, event: &TranscriptEvent) -> std::io::Result<()> {
+        let time = DateTime::from_timestamp(timestamp, 0).unwrap_or_default().with_timezone(&Local);
+        let line = match self.format {
+            TranscriptFormat::Text => format!("[{}] {}\n", time.format("%H:%M:%S"), text_line(event)),
+            TranscriptFormat::Jsonl => {
+                let line = TranscriptLine { time: time.to_rfc3339(), timestamp, event };
+                format!("{}\n", serde_json::to_string(&line)?)
+            }
+        };
+        self.file_for(transcript_name(event), time.date_naive())?.write_all(line.as_bytes())
+    }
+
+    // The file of the transcript for that day, a new day starts a new file
+    fn file_for(&mut self, name: &str, date: NaiveDate) -> std::io::Result<&mut File> {
+        self.writes += 1;
+        if self.open.get(name).is_none_or(|open| open.date != date) {
+            // Channels come and go, so their files are not all kept open
+            if !self.open.contains_key(name) && self.open.len() >= MAX_OPEN_FILES {
+                let least_recent = self.open.iter().min_by_key(|(_, open)| open.last_write).map(|(name, _)| name.clone());
+                if let Some(least_recent) = least_recent {
+                    self.open.remove(&least_recent);
+                }
+            }
+            let directory = self.directory.join(name);
+            fs::create_dir_all(&directory)?;
+            let extension = match self.format {
+                TranscriptFormat::Text => "log",
+                TranscriptFormat::Jsonl => "jsonl",
+            };
+            let path = directory.join(format!("{}.{}", date.format("%Y-%m-%d"), extension));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            self.open.insert(name.to_string(), OpenFile { date, file, last_write: 0 });
+        }
+        let open = self.open.get_mut(name).expect("transcript file was just opened");
+        open.last_write = self.writes;
+        Ok(&mut open.file)
+    }
+}
+
+// Channel names are restricted to letters, digits, '-' and '_', so they are safe as directory names
+fn transcript_name(event: &TranscriptEvent) -> &str {
+    match event {
+        TranscriptEvent::Message { channel, .. }
+        | TranscriptEvent::Join { channel, .. }
+        | TranscriptEvent::Leave { channel, .. }
+        | TranscriptEvent::Notice { channel, .. } => channel.trim_start_matches('#'),
+        TranscriptEvent::Direct { .. } => PRIVATE_TRANSCRIPT,
+    }
+}
+
+// IRC-style line without the time, every event stays on a single line
+fn text_line(event: &TranscriptEvent) -> String {
+    let one_line = |text: &str| text.replace(['\r', '\n'], " ");
+    match event {
+        TranscriptEvent::Message { from, text, .. } => format!("<{}> {}", from, one_line(text)),
+        TranscriptEvent::Join { channel, username } => format!("--> {} has joined {}", username, channel),
+        TranscriptEvent::Leave { channel, username } => format!("<-- {} has left {}", username, channel),
+        TranscriptEvent::Notice { text, .. } => format!("-!- {}", one_line(text)),
+        TranscriptEvent::Direct { from, to, text } => format!("<{} -> {}> {}", from, to, one_line(text)),
+    }
+}
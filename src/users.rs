@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+pub const MIN_USERNAME_LEN: usize = 2;
+pub const MAX_USERNAME_LEN: usize = 24;
+
+// Names nobody may log in with: the client shows server notices as SERVER and @all mentions everyone
+const RESERVED_USERNAMES: &[&str] = &["server", "system", "admin", "all"];
+
+// Why a username was refused at login
+#[derive(Debug, PartialEq)]
+pub enum UsernameError {
+    Empty,
+    Length,
+    InvalidCharacter(char),
+    Reserved,
+    Taken,
+    Lookalike, // looks the same as a registered username
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "Username cannot be empty."),
+            UsernameError::Length => write!(f, "Usernames must be {} to {} characters long.", MIN_USERNAME_LEN, MAX_USERNAME_LEN),
+            UsernameError::InvalidCharacter(c) => write!(f, "Usernames may only contain letters, digits, '-' and '_', not {:?}.", c),
+            UsernameError::Reserved => write!(f, "This username is reserved. Please choose a different one."),
+            UsernameError::Taken => write!(f, "Username is already taken. Please choose a different one."),
+            UsernameError::Lookalike => write!(f, "This username looks like a registered one. Please choose a different one."),
+        }
+    }
+}
+
+// The form a username is checked and stored in: NFKC turns compatibility characters such as
+// fullwidth letters into the plain ones, and combines accents with their letters
+pub fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect()
+}
+
+// Check a normalized username against the rules, whether or not someone is using it.
+// Invisible characters such as zero-width spaces are not letters, so they are refused here.
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    if username.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    let len = username.chars().count();
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        return Err(UsernameError::Length);
+    }
+    if let Some(c) = username.chars().find(|c| !(c.is_alphanumeric() || *c == '-' || *c == '_')) {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    if RESERVED_USERNAMES.iter().any(|reserved| looks_reserved(reserved, username)) {
+        return Err(UsernameError::Reserved);
+    }
+    Ok(())
+}
+
+// Usernames sharing a key look alike and belong to the same user: they differ only in case or in
+// characters that are easily confused, such as a Cyrillic "а" for a Latin "a" or "0" for "O"
+// (the skeleton of Unicode TS #39). Case and confusables do not always agree, "I" is an "i" in
+// lower case but looks like an "l", so there is one key with the case folded first and one with
+// the skeleton taken first.
+pub fn username_keys(username: &str) -> [String; 2] {
+    [
+        skeleton(&username.to_lowercase()).collect::<String>().to_lowercase(),
+        skeleton(username).collect::<String>().to_lowercase(),
+    ]
+}
+
+// Reserved names are reserved in every case, and an upper case "I" passes for an "l" or a "1",
+// so "adm1n" is refused because it looks like "admIn". A lower case "i" does not, "ali" is fine.
+fn looks_reserved(reserved: &str, username: &str) -> bool {
+    look_alike(reserved, username) || look_alike(&reserved.replace('i', "I"), username)
+}
+
+// Whether two usernames would pass for each other
+pub fn look_alike(a: &str, b: &str) -> bool {
+    let (a, b) = (username_keys(a), username_keys(b));
+    a.iter().any(|key| b.contains(key))
+}
+
+// The usernames claimed by live and detached sessions. A name is checked and claimed in one
+// step, so two logins racing for the same name cannot both get it, and a name that looks like
+// one in use is taken as well.
+#[derive(Default)]
+pub struct UserRegistry {
+    usernames: HashMap<usize, String>, // client token -> username as chosen by the user
+    tokens: HashMap<String, usize>, // both keys of every username -> client token
+}
+
+impl UserRegistry {
+    // Claim a valid username for the connection, or say why it cannot have it
+    pub fn reserve(&mut self, client_token: usize, username: &str) -> Result<(), UsernameError> {
+        validate_username(username)?;
+        let keys = username_keys(username);
+        if keys.iter().any(|key| self.tokens.contains_key(key)) {
+            return Err(UsernameError::Taken);
+        }
+        self.release(client_token);
+        self.usernames.insert(client_token, username.to_string());
+        for key in keys {
+            self.tokens.insert(key, client_token);
+        }
+        Ok(())
+    }
+
+    // Give the username of the connection back, returns it if there was one
+    pub fn release(&mut self, client_token: usize) -> Option<String> {
+        let username = self.usernames.remove(&client_token)?;
+        for key in username_keys(&username) {
+            self.tokens.remove(&key);
+        }
+        Some(username)
+    }
+
+    // Token of the session using the username or one that looks like it
+    pub fn token_of(&self, username: &str) -> Option<usize> {
+        username_keys(username).iter().find_map(|key| self.tokens.get(key).copied())
+    }
+
+    // The username as its user spelled it
+    pub fn username(&self, client_token: usize) -> Option<&str> {
+        self.usernames.get(&client_token).map(String::as_str)
+    }
+
+    pub fn is_online(&self, username: &str) -> bool {
+        self.token_of(username).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.usernames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.usernames.is_empty()
+    }
+
+    // Client tokens and usernames, ordered by token
+    pub fn users(&self) -> Vec<(usize, String)> {
+        let mut users: Vec<(usize, String)> = self.usernames.iter().map(|(token, name)| (*token, name.clone())).collect();
+        users.sort();
+        users
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(username: &str) -> Result<(), UsernameError> {
+        validate_username(&normalize_username(username))
+    }
+
+    #[test]
+    fn normalizes_compatibility_characters() {
+        assert_eq!(normalize_username("  ａｌｉｃｅ "), "alice");
+        assert_eq!(normalize_username("Ali\u{0301}ce"), "Al\u{00ED}ce");
+    }
+
+    #[test]
+    fn lookalikes_of_alice() {
+        let cyrillic = "\u{0430}lice";
+        assert_eq!(check(cyrillic), Ok(()));
+        assert!(look_alike("alice", cyrillic));
+        assert!(look_alike("alice", &normalize_username("ａｌｉｃｅ")));
+        assert!(look_alike("alice", "a1ice"));
+        assert!(look_alike("alice", "ALICE"));
+        assert!(!look_alike("alice", "alicia"));
+    }
+
+    #[test]
+    fn refuses_invisible_characters() {
+        assert_eq!(check("ali\u{200B}ce"), Err(UsernameError::InvalidCharacter('\u{200B}')));
+        assert_eq!(check("ali\u{200D}ce"), Err(UsernameError::InvalidCharacter('\u{200D}')));
+        assert_eq!(check("\u{FEFF}alice"), Err(UsernameError::InvalidCharacter('\u{FEFF}')));
+    }
+
+    #[test]
+    fn checks_length_and_characters() {
+        assert_eq!(check("   "), Err(UsernameError::Empty));
+        assert_eq!(check("a"), Err(UsernameError::Length));
+        assert_eq!(check(&"a".repeat(MAX_USERNAME_LEN + 1)), Err(UsernameError::Length));
+        assert_eq!(check("bob smith"), Err(UsernameError::InvalidCharacter(' ')));
+        assert_eq!(check("bob_smith-2"), Ok(()));
+    }
+
+    #[test]
+    fn refuses_reserved_names_and_their_lookalikes() {
+        for name in ["server", "SERVER", "System", "admin", "adm1n", "admIn", "ADM1N", "\u{0430}dmin", "all", "a11", "AII"] {
+            assert_eq!(check(name), Err(UsernameError::Reserved), "{}", name);
+        }
+        for name in ["Ali", "ali", "Ail", "ail", "admiral", "servers"] {
+            assert_eq!(check(name), Ok(()), "{}", name);
+        }
+    }
+
+    #[test]
+    fn upper_case_i_looks_like_l() {
+        // Folding the case first makes "Ian" an "ian", taking the skeleton first makes it a "lan"
+        assert!(look_alike("Ian", "ian"));
+        assert!(look_alike("Ian", "lan"));
+        assert!(!look_alike("ian", "lan"));
+    }
+
+    #[test]
+    fn registry_claims_every_spelling() {
+        let mut users = UserRegistry::default();
+        assert_eq!(users.reserve(1, "Alice"), Ok(()));
+        assert_eq!(users.reserve(2, "alice"), Err(UsernameError::Taken));
+        assert_eq!(users.reserve(2, "a1ice"), Err(UsernameError::Taken));
+        assert_eq!(users.token_of("ALICE"), Some(1));
+        assert_eq!(users.username(1), Some("Alice"));
+
+        assert_eq!(users.reserve(3, "Ian"), Ok(()));
+        assert_eq!(users.reserve(4, "lan"), Err(UsernameError::Taken));
+        assert_eq!(users.reserve(4, "ian"), Err(UsernameError::Taken));
+
+        assert_eq!(users.release(1), Some("Alice".to_string()));
+        assert!(!users.is_online("alice"));
+        assert_eq!(users.reserve(2, "alice"), Ok(()));
+        assert_eq!(users.users(), vec![(2, "alice".to_string()), (3, "Ian".to_string())]);
+    }
+}
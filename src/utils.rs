@@ -2,7 +2,7 @@ use tokio::sync::Mutex;
 use tokio::io::AsyncWriteExt;
 use std::sync::Arc;
 use colored::*;
-use chrono::Local;
+use chrono::{DateTime, Local};
 use std::marker::Unpin; 
 
 // Helper function to write to a stream
@@ -11,28 +11,50 @@ pub async fn write_to_stream(write_stream: &Arc<Mutex<impl AsyncWriteExt + Unpin
     stream.write_all(message.as_bytes()).await
 }
 
-#[allow(dead_code)] //it is used in client, just compiler is confused
-pub fn format_message(_username: &str, message: &str, is_server_message: bool, my_username: &str) -> String {
-    let timestamp = Local::now().format("[%d.%m.%Y %H:%M]").to_string();
+// Usernames with characters beyond ASCII are flagged wherever they are shown, since they can
+// pass for someone else's name
+pub fn display_username(username: &str) -> String {
+    if username.is_ascii() {
+        username.to_string()
+    } else {
+        format!("{} [non-ASCII]", username)
+    }
+}
+
+// Render a Unix timestamp from the server in local time, 0 (unknown) falls back to now
+pub fn format_timestamp(timestamp: i64) -> String {
+    let time = DateTime::from_timestamp(timestamp, 0)
+        .filter(|_| timestamp > 0)
+        .map(|time| time.with_timezone(&Local))
+        .unwrap_or_else(Local::now);
+    time.format("[%d.%m.%Y %H:%M]").to_string()
+}
+
+pub fn format_message(channel: Option<&str>, username: &str, message: &str, is_server_message: bool, my_username: &str, timestamp: i64) -> String {
+    let timestamp = format_timestamp(timestamp);
+    // Show which channel the line belongs to, if any
+    let timestamp = match channel {
+        Some(channel) => format!("{} {}", timestamp.black(), format!("[{}]", channel).cyan()),
+        None => timestamp.black().to_string(),
+    };
     if is_server_message {
-        format!("{} {}", timestamp.black(), message.magenta())
+        format!("{} {}", timestamp, message.magenta())
     } else {
-        // Split the message into username and content
-        let mut parts = message.splitn(2, ':');
-        let message_username = parts.next().unwrap_or("").trim(); // Extract the username part
-        let message_content = parts.next().unwrap_or("").trim(); // Extract the message content
-        let colored_username = if message_username == my_username {
-            message_username.green().to_string() 
+        // The sender and the content arrive as separate fields of the chat frame
+        let message_content = message.trim();
+        let colored_username = if username == my_username {
+            display_username(username).green().to_string() 
         } else {
-            message_username.blue().to_string() 
+            display_username(username).blue().to_string() 
         };
 
         // Apply mention highlighting
-        let formatted_content = if message_content.contains(&format!("@{}", my_username)) || message_content.contains("@all") {
+        let mention = format!("@{}", my_username);
+        let formatted_content = if message_content.contains(&mention) || message_content.contains("@all") {
             message_content
                 .split_whitespace()
                 .map(|word| {
-                    if word == &format!("@{}", my_username) || word == "@all" {
+                    if word == mention || word == "@all" {
                         word.red().bold().to_string()
                     } else {
                         word.to_string()
@@ -46,6 +68,26 @@ pub fn format_message(_username: &str, message: &str, is_server_message: bool, m
         };
 
         // Combine the timestamp, colored username, and formatted content
-        format!("{} {}: {}", timestamp.black(), colored_username, formatted_content)
+        format!("{} {}: {}", timestamp, colored_username, formatted_content)
     }
 }
+
+// Private messages get their own marker and color so they never blend into channel traffic
+pub fn format_direct_message(from: &str, to: &str, message: &str, my_username: &str, timestamp: i64) -> String {
+    let timestamp = format_timestamp(timestamp);
+    let direction = if from == my_username {
+        format!("to {}", display_username(to))
+    } else {
+        format!("from {}", display_username(from))
+    };
+    format!("{} {} {}", timestamp.black(), format!("[DM {}]", direction).yellow().bold(), message.trim().yellow())
+}
+
+// Replayed history is dimmed and uncolored so it stands apart from the live conversation
+pub fn format_backlog_message(channel: &str, username: Option<&str>, message: &str, timestamp: i64) -> String {
+    let line = match username {
+        Some(username) => format!("{} [{}] {}: {}", format_timestamp(timestamp), channel, display_username(username), message.trim()),
+        None => format!("{} [{}] {}", format_timestamp(timestamp), channel, message.trim()),
+    };
+    line.dimmed().to_string()
+}